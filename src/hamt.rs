@@ -1,128 +1,2642 @@
 //! An unordered map and set type implemented as hash array mapped tries
 //!
 //! The tries use a keyed hash with new random keys generated for each container, so the ordering
-//! of a set of keys in a hash table is randomized.
+//! of a set of keys in a hash table is randomized. Any other `BuildHasher` can be used instead,
+//! by creating the container with `with_hasher`.
 //!
-//! Unlike hash tables, hash array mapped tries are persistent.
+//! Two containers that were built separately therefore place their keys differently, and an
+//! operation on both of them, like a union or `==`, has to rehash one side or look its keys up
+//! one by one. Containers created from the same `HasherFamily` share one hasher, so those
+//! operations can merge or compare their tries node by node, as they do for versions of one
+//! container. Containers count as sharing a hasher only if they share the same instance, so this
+//! holds for hashers like `FixedState` too: two maps that were each given `FixedState::new()`
+//! hash alike, but merging them still rehashes one side.
+//!
+//! For an order that is the same in every run and on every target, use `FixedState` as the
+//! hasher. The shape of a trie only depends on the hashes of its keys, so two containers with the
+//! same keys, hashed by `FixedState`, are iterated in the same order however they were built. The
+//! one exception is keys whose 64 bit hashes are all equal. Those share a list, and their order
+//! in it depends on the order they were inserted and removed in.
+//!
+//! Unlike hash tables, hash array mapped tries are persistent. Nodes are reference counted, so
+//! cloning a container is O(1) and the clone shares its whole structure with the original.
+//! `SyncHashMap` and `SyncHashSet` count references atomically, so their versions can also be
+//! shared between threads.
+
+use std::borrow::{Borrow, Cow};
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::iter::FusedIterator;
+use std::ops::Deref;
+use std::rc::Rc;
+use std::slice;
+use std::sync::Arc;
+
+use self::HAMT::{Branches, Buckets};
+use self::Slot::{Child, Data};
+
+/// the bitsets of a node, one bit per slot. They have the same width on every target, so the
+/// shape of a trie and the order it is iterated in only depend on the hashes of its keys.
+type Bitmap = u32;
+
+/// hash bits consumed by each level of the trie, enough to pick one bit of a `Bitmap`
+const BITS_PER_LEVEL: u32 = Bitmap::BITS.trailing_zeros();
+
+/// number of slots in a node, one per bit of its bitsets
+const BRANCH_FACTOR: usize = 1 << BITS_PER_LEVEL;
+
+/// levels it takes to use up all 64 bits of a hash. Two keys that are still in the same slot at
+/// this depth have equal hashes, so they can only live together in one `Buckets` leaf.
+const MAX_DEPTH: u32 = u64::BITS.div_ceil(BITS_PER_LEVEL);
+
+/// returns index to bitset for the chunk of the hash used at `depth`
+fn split_hash(h: u64, depth: u32) -> usize {
+    debug_assert!(depth < MAX_DEPTH);
+    ((h >> (depth * BITS_PER_LEVEL)) as usize) & (BRANCH_FACTOR - 1)
+}
+
+/// The reference-counted pointer that a container's nodes are shared through. With `RcPointer`
+/// the containers stay on one thread; with `ArcPointer` they are `Send` and `Sync`, so a version
+/// can be handed to other threads and read from all of them at once.
+///
+/// The trait is sealed: comparisons and set operations skip subtrees whose pointers are
+/// `ptr_eq`, so only the two kinds defined here can implement it.
+pub trait PointerKind: sealed::Sealed + 'static {
+    /// pointer to a shared `T`
+    type Pointer<T>: Deref<Target = T> + Clone;
+
+    /// Move `value` into a new pointer
+    fn new<T>(value: T) -> Self::Pointer<T>;
+
+    /// Return a mutable reference to the pointee, cloning it first if it is shared
+    fn make_mut<T: Clone>(ptr: &mut Self::Pointer<T>) -> &mut T;
+
+    /// Return true if both pointers point to the same allocation
+    fn ptr_eq<T>(a: &Self::Pointer<T>, b: &Self::Pointer<T>) -> bool;
+}
+
+mod sealed {
+    /// supertrait of `PointerKind` that other crates can't name, so they can't implement it
+    pub trait Sealed {}
+
+    impl Sealed for super::RcPointer {}
+    impl Sealed for super::ArcPointer {}
+}
+
+/// Nodes shared through `Rc`, the default
+pub enum RcPointer {}
+
+impl PointerKind for RcPointer {
+    type Pointer<T> = Rc<T>;
+
+    fn new<T>(value: T) -> Rc<T> { Rc::new(value) }
+
+    fn make_mut<T: Clone>(ptr: &mut Rc<T>) -> &mut T { Rc::make_mut(ptr) }
+
+    fn ptr_eq<T>(a: &Rc<T>, b: &Rc<T>) -> bool { Rc::ptr_eq(a, b) }
+}
+
+/// Nodes shared through `Arc`, for containers that are used from several threads
+pub enum ArcPointer {}
+
+impl PointerKind for ArcPointer {
+    type Pointer<T> = Arc<T>;
+
+    fn new<T>(value: T) -> Arc<T> { Arc::new(value) }
+
+    fn make_mut<T: Clone>(ptr: &mut Arc<T>) -> &mut T { Arc::make_mut(ptr) }
+
+    fn ptr_eq<T>(a: &Arc<T>, b: &Arc<T>) -> bool { Arc::ptr_eq(a, b) }
+}
+
+#[derive(Clone)]
+struct Bucket<K,V> {
+    hash: u64,
+    key: K,
+    value: V
+}
+
+impl<K: Clone, V> Bucket<K,V> {
+    fn try_map<W, E, F>(&self, f: &mut F) -> Result<Bucket<K,W>, E>
+            where F: FnMut(&K, &V) -> Result<W, E> {
+        Ok(Bucket { hash: self.hash, key: self.key.clone(), value: f(&self.key, &self.value)? })
+    }
+}
+
+/// The buckets of two or more keys whose full hash is `hash`. These only exist when distinct keys
+/// collide on all 64 bits, and are scanned linearly.
+#[derive(Clone)]
+struct Collision<K,V> {
+    hash: u64,
+    buckets: Vec<Bucket<K,V>>
+}
+
+impl<K,V> Collision<K,V> {
+    fn find<Q>(&self, key: &Q) -> Option<&Bucket<K,V>> where K: Borrow<Q>, Q: Eq + ?Sized {
+        self.buckets.iter().find(|b| key.eq(b.key.borrow()))
+    }
+
+    /// Add a bucket for `key`, or replace the value in the one it already has
+    fn insert(&mut self, bucket: Bucket<K,V>) -> Option<V> where K: Eq {
+        match self.buckets.iter_mut().find(|b| bucket.key.eq(&b.key)) {
+            Some(b) => Some(std::mem::replace(&mut b.value, bucket.value)),
+            None => {
+                self.buckets.push(bucket);
+                None
+            }
+        }
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> Option<Bucket<K,V>> where K: Borrow<Q>, Q: Eq + ?Sized {
+        let i = self.buckets.iter().position(|b| key.eq(b.key.borrow()))?;
+        Some(self.buckets.swap_remove(i))
+    }
+}
+
+/// A node in the CHAMP layout: each slot of the node is either empty, holds a single key inline
+/// in `data`, or holds a child in `nodes` for the keys that share that slot. The two bitsets say
+/// which slots are which, and each vector only stores the slots that are present, in slot order,
+/// so an index into one is the popcount of its bitset below the slot's bit.
+///
+/// Outside the root, a node always holds at least two keys and never holds just a lone
+/// `Buckets` child, so every set of keys has exactly one shape. `size` counts all the keys below
+/// the node, so that operations which reuse whole subtrees still know how big their result is.
+struct Node<K,V,P: PointerKind> {
+    datamap: Bitmap,
+    nodemap: Bitmap,
+    size: usize,
+    data: Vec<Bucket<K,V>>,
+    nodes: Vec<Link<K,V,P>>
+}
+
+/// a shared pointer to a child of a node
+type Link<K,V,P> = <P as PointerKind>::Pointer<HAMT<K,V,P>>;
+
+#[allow(clippy::upper_case_acronyms)]
+enum HAMT<K,V,P: PointerKind> {
+    Buckets (Collision<K,V>),
+    Branches (Node<K,V,P>)
+}
+
+/// what one slot of a node can hold
+enum Slot<K,V,P: PointerKind> {
+    Data (Bucket<K,V>),
+    Child (Link<K,V,P>)
+}
+
+/// a borrowed view of one slot of a node, which may be empty
+enum SlotRef<'a,K,V,P: PointerKind> {
+    Empty,
+    Data (&'a Bucket<K,V>),
+    Child (&'a Link<K,V,P>)
+}
+
+impl<K,V,P: PointerKind> Clone for SlotRef<'_,K,V,P> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<K,V,P: PointerKind> Copy for SlotRef<'_,K,V,P> {}
+
+/// Which keys a structural merge of two tries keeps: those only in the left one, those in both
+/// (with the left one's value) and those only in the right one
+#[derive(Clone, Copy)]
+struct Keep {
+    left: bool,
+    both: bool,
+    right: bool
+}
+
+/// How a structural merge of a trie of `A` values with a trie of `B` values builds a trie of `C`
+/// values. The slots that only one side has are handed over whole, along with the depth of the
+/// node they are in, so that they can be reused without looking inside them.
+trait Merge<K,A,B,C,P: PointerKind> {
+    /// Return what goes in a slot where both sides hold the same child, if that can be told
+    /// without looking inside it
+    fn shared(&mut self, _a: &Link<K,A,P>, _b: &Link<K,B,P>) -> Option<Option<Slot<K,C,P>>> {
+        None
+    }
+
+    /// Return what goes in place of a slot that only the left trie has
+    fn left(&mut self, slot: SlotRef<'_,K,A,P>, depth: u32) -> Option<Slot<K,C,P>>;
+
+    /// Return what goes in place of a slot that only the right trie has
+    fn right(&mut self, slot: SlotRef<'_,K,B,P>, depth: u32) -> Option<Slot<K,C,P>>;
+
+    /// Return the value for a key that both tries have, or `None` to leave the key out
+    fn both(&mut self, key: &K, a: &A, b: &B) -> Option<C>;
+}
+
+/// `union_with`: keys on one side only are kept as they are
+struct UnionWith<F>(F);
+
+/// `intersection_with`: keys on one side only are dropped
+struct IntersectionWith<F>(F);
+
+/// `restrict_keys` and `without_keys`: keep the keys of the left trie that are `inside` the right
+/// one, or those that aren't
+struct Restrict {
+    inside: bool
+}
+
+/// `merge`: every entry goes through one of the three functions
+struct MergeWith<F,G,H> {
+    left: F,
+    both: G,
+    right: H
+}
+
+impl<K: Clone, V: Clone, P: PointerKind> Clone for Node<K,V,P> {
+    fn clone(&self) -> Node<K,V,P> {
+        Node {
+            datamap: self.datamap,
+            nodemap: self.nodemap,
+            size: self.size,
+            data: self.data.clone(),
+            nodes: self.nodes.clone()
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, P: PointerKind> Clone for HAMT<K,V,P> {
+    fn clone(&self) -> HAMT<K,V,P> {
+        match *self {
+            Buckets(ref leaf) => Buckets(leaf.clone()),
+            Branches(ref node) => Branches(node.clone())
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, P: PointerKind> Merge<K,V,V,V,P> for Keep {
+    fn shared(&mut self, a: &Link<K,V,P>, b: &Link<K,V,P>) -> Option<Option<Slot<K,V,P>>> {
+        match P::ptr_eq(a, b) {
+            true => Some(if self.both { Some(Child(a.clone())) } else { None }),
+            false => None
+        }
+    }
+
+    fn left(&mut self, slot: SlotRef<'_,K,V,P>, _depth: u32) -> Option<Slot<K,V,P>> {
+        if self.left { slot.to_slot() } else { None }
+    }
+
+    fn right(&mut self, slot: SlotRef<'_,K,V,P>, _depth: u32) -> Option<Slot<K,V,P>> {
+        if self.right { slot.to_slot() } else { None }
+    }
+
+    fn both(&mut self, _key: &K, a: &V, _b: &V) -> Option<V> {
+        if self.both { Some(a.clone()) } else { None }
+    }
+}
+
+impl<K: Clone, V: Clone, P: PointerKind, F> Merge<K,V,V,V,P> for UnionWith<F>
+        where F: FnMut(&K, &V, &V) -> V {
+    fn left(&mut self, slot: SlotRef<'_,K,V,P>, _depth: u32) -> Option<Slot<K,V,P>> {
+        slot.to_slot()
+    }
+
+    fn right(&mut self, slot: SlotRef<'_,K,V,P>, _depth: u32) -> Option<Slot<K,V,P>> {
+        slot.to_slot()
+    }
+
+    fn both(&mut self, key: &K, a: &V, b: &V) -> Option<V> {
+        Some((self.0)(key, a, b))
+    }
+}
+
+impl<K: Clone, V: Clone, B, P: PointerKind> Merge<K,V,B,V,P> for Restrict {
+    fn left(&mut self, slot: SlotRef<'_,K,V,P>, _depth: u32) -> Option<Slot<K,V,P>> {
+        if self.inside { None } else { slot.to_slot() }
+    }
+
+    fn right(&mut self, _slot: SlotRef<'_,K,B,P>, _depth: u32) -> Option<Slot<K,V,P>> { None }
+
+    fn both(&mut self, _key: &K, a: &V, _b: &B) -> Option<V> {
+        if self.inside { Some(a.clone()) } else { None }
+    }
+}
+
+impl<K,A,B,C,P: PointerKind,F> Merge<K,A,B,C,P> for IntersectionWith<F>
+        where F: FnMut(&K, &A, &B) -> C {
+    fn left(&mut self, _slot: SlotRef<'_,K,A,P>, _depth: u32) -> Option<Slot<K,C,P>> { None }
+
+    fn right(&mut self, _slot: SlotRef<'_,K,B,P>, _depth: u32) -> Option<Slot<K,C,P>> { None }
+
+    fn both(&mut self, key: &K, a: &A, b: &B) -> Option<C> {
+        Some((self.0)(key, a, b))
+    }
+}
+
+impl<K: Clone,A,B,C,P: PointerKind,F,G,H> Merge<K,A,B,C,P> for MergeWith<F,G,H>
+        where F: FnMut(&K, &A) -> Option<C>,
+              G: FnMut(&K, &A, &B) -> Option<C>,
+              H: FnMut(&K, &B) -> Option<C> {
+    fn left(&mut self, slot: SlotRef<'_,K,A,P>, depth: u32) -> Option<Slot<K,C,P>> {
+        Node::filter_map_slot(slot, &mut self.left, depth)
+    }
+
+    fn right(&mut self, slot: SlotRef<'_,K,B,P>, depth: u32) -> Option<Slot<K,C,P>> {
+        Node::filter_map_slot(slot, &mut self.right, depth)
+    }
+
+    fn both(&mut self, key: &K, a: &A, b: &B) -> Option<C> {
+        (self.both)(key, a, b)
+    }
+}
+
+impl<K,V,P: PointerKind> HAMT<K,V,P> {
+    fn len(&self) -> usize {
+        match *self {
+            Buckets(ref leaf) => leaf.buckets.len(),
+            Branches(ref node) => node.size
+        }
+    }
+}
+
+impl<K,V,P: PointerKind> Slot<K,V,P> {
+    fn len(&self) -> usize {
+        match *self {
+            Data(_) => 1,
+            Child(ref child) => child.len()
+        }
+    }
+
+    /// Return what a slot should hold for some buckets whose keys all have the full hash `hash`
+    fn from_buckets(hash: u64, mut buckets: Vec<Bucket<K,V>>) -> Option<Slot<K,V,P>> {
+        match buckets.len() {
+            0 => None,
+            1 => buckets.pop().map(Data),
+            _ => Some(Child(P::new(Buckets(Collision { hash, buckets }))))
+        }
+    }
+}
+
+impl<'a,K,V,P: PointerKind> SlotRef<'a,K,V,P> {
+    /// If every key in the slot has the same hash, return it and their buckets
+    fn single_hash(self) -> Option<(u64, &'a [Bucket<K,V>])> {
+        match self {
+            SlotRef::Empty => None,
+            SlotRef::Data(b) => Some((b.hash, slice::from_ref(b))),
+            SlotRef::Child(child) => match **child {
+                Buckets(ref leaf) => Some((leaf.hash, &leaf.buckets[..])),
+                Branches(_) => None
+            }
+        }
+    }
+
+    /// Return the node in the slot, if it holds a `Branches` child
+    fn branch(self) -> Option<&'a Node<K,V,P>> {
+        match self {
+            SlotRef::Child(child) => match **child {
+                Branches(ref node) => Some(node),
+                Buckets(_) => None
+            },
+            _ => None
+        }
+    }
+}
+
+impl<'a,K: Clone,V: Clone,P: PointerKind> SlotRef<'a,K,V,P> {
+    fn to_slot(self) -> Option<Slot<K,V,P>> {
+        match self {
+            SlotRef::Empty => None,
+            SlotRef::Data(b) => Some(Data(b.clone())),
+            SlotRef::Child(child) => Some(Child(child.clone()))
+        }
+    }
+
+    /// Return the contents of a non-empty slot at `depth - 1` as a node at `depth`. Only a
+    /// `Branches` child is a node already; anything else is put in a new one on its own.
+    fn to_node(self, depth: u32) -> Cow<'a, Node<K,V,P>> {
+        let mut node = Node::empty();
+        match self {
+            SlotRef::Empty => (),
+            SlotRef::Data(b) => node.put(1 << split_hash(b.hash, depth), Data(b.clone())),
+            SlotRef::Child(child) => match **child {
+                Branches(ref branch) => return Cow::Borrowed(branch),
+                Buckets(ref leaf) => {
+                    node.put(1 << split_hash(leaf.hash, depth), Child(child.clone()))
+                }
+            }
+        }
+        Cow::Owned(node)
+    }
+}
+
+impl<K,V,P: PointerKind> Node<K,V,P> {
+    fn empty() -> Node<K,V,P> {
+        Node { datamap: 0, nodemap: 0, size: 0, data: Vec::new(), nodes: Vec::new() }
+    }
+
+    fn data_index(&self, bit: Bitmap) -> usize { (self.datamap & (bit - 1)).count_ones() as usize }
+
+    fn node_index(&self, bit: Bitmap) -> usize { (self.nodemap & (bit - 1)).count_ones() as usize }
+
+    fn slot(&self, bit: Bitmap) -> SlotRef<'_,K,V,P> {
+        if self.datamap & bit != 0 {
+            SlotRef::Data(&self.data[self.data_index(bit)])
+        } else if self.nodemap & bit != 0 {
+            SlotRef::Child(&self.nodes[self.node_index(bit)])
+        } else {
+            SlotRef::Empty
+        }
+    }
+
+    fn put(&mut self, bit: Bitmap, slot: Slot<K,V,P>) {
+        self.size += slot.len();
+        match slot {
+            Data(bucket) => {
+                let i = self.data_index(bit);
+                self.datamap |= bit;
+                self.data.insert(i, bucket);
+            }
+            Child(child) => {
+                let i = self.node_index(bit);
+                self.nodemap |= bit;
+                self.nodes.insert(i, child);
+            }
+        }
+    }
+
+    fn take(&mut self, bit: Bitmap) -> Slot<K,V,P> {
+        let slot = if self.datamap & bit != 0 {
+            let i = self.data_index(bit);
+            self.datamap &= !bit;
+            Data(self.data.remove(i))
+        } else {
+            let i = self.node_index(bit);
+            self.nodemap &= !bit;
+            Child(self.nodes.remove(i))
+        };
+        self.size -= slot.len();
+        slot
+    }
+
+    /// Replace what a slot holds with `slot`, which holds the same keys
+    fn replace(&mut self, bit: Bitmap, slot: Slot<K,V,P>) {
+        let size = self.size;
+        self.take(bit);
+        self.put(bit, slot);
+        self.size = size;
+    }
+
+    /// Turn a node built for the slot of some other node into what that slot should hold, so
+    /// the trie keeps its one shape for each set of keys
+    fn into_slot(mut self) -> Option<Slot<K,V,P>> {
+        match (self.data.len(), self.nodes.len()) {
+            (0, 0) => None,
+            (1, 0) => self.data.pop().map(Data),
+            (0, 1) if matches!(*self.nodes[0], Buckets(..)) => self.nodes.pop().map(Child),
+            _ => Some(Child(P::new(Branches(self))))
+        }
+    }
+
+    /// Build the node at `depth` that tells apart two slots whose keys have different hashes.
+    /// This always ends before `MAX_DEPTH`, because different hashes differ in some chunk.
+    fn join(a: Slot<K,V,P>, a_hash: u64, b: Slot<K,V,P>, b_hash: u64, depth: u32) -> Node<K,V,P> {
+        debug_assert!(a_hash != b_hash);
+        let a_bit = 1 << split_hash(a_hash, depth);
+        let b_bit = 1 << split_hash(b_hash, depth);
+        let mut node = Node::empty();
+        if a_bit == b_bit {
+            let child = Node::join(a, a_hash, b, b_hash, depth + 1);
+            node.put(a_bit, Child(P::new(Branches(child))));
+        } else {
+            node.put(a_bit, a);
+            node.put(b_bit, b);
+        }
+        node
+    }
+}
+
+impl<K: Eq + Clone, V: Clone, P: PointerKind> Node<K,V,P> {
+    /// Insert into the node at `depth` below the root, copying each node on the way down that is
+    /// shared with another version. Returns the value that was replaced, if the key was already
+    /// present.
+    fn insert(&mut self, bucket: Bucket<K,V>, depth: u32) -> Option<V> {
+        let bit = 1 << split_hash(bucket.hash, depth);
+
+        if self.datamap & bit != 0 {
+            let i = self.data_index(bit);
+            let old = &mut self.data[i];
+            if old.hash == bucket.hash && old.key == bucket.key {
+                return Some(std::mem::replace(&mut old.value, bucket.value));
+            }
+            // two keys now share the slot, so they move down into a child together
+            let old = match self.take(bit) { Data(b) => b, Child(_) => unreachable!() };
+            let child = if old.hash == bucket.hash {
+                Buckets(Collision { hash: old.hash, buckets: vec![old, bucket] })
+            } else {
+                let (old_hash, hash) = (old.hash, bucket.hash);
+                Branches(Node::join(Data(old), old_hash, Data(bucket), hash, depth + 1))
+            };
+            self.put(bit, Child(P::new(child)));
+            return None;
+        }
+
+        if self.nodemap & bit == 0 {
+            self.put(bit, Data(bucket));
+            return None;
+        }
+
+        let i = self.node_index(bit);
+        if let Buckets(ref leaf) = *self.nodes[i] {
+            if leaf.hash != bucket.hash {
+                // the collision has to move down a level, next to the new key
+                let leaf_hash = leaf.hash;
+                let hash = bucket.hash;
+                let leaf = self.nodes[i].clone();
+                let child = Node::join(Child(leaf), leaf_hash, Data(bucket), hash, depth + 1);
+                self.nodes[i] = P::new(Branches(child));
+                self.size += 1;
+                return None;
+            }
+        }
+        let replaced = match *P::make_mut(&mut self.nodes[i]) {
+            Buckets(ref mut leaf) => leaf.insert(bucket),
+            Branches(ref mut node) => node.insert(bucket, depth + 1)
+        };
+        if replaced.is_none() {
+            self.size += 1;
+        }
+        replaced
+    }
+
+    /// Remove a key that is known to be in the node at `depth`, copying each node on the way
+    /// down that is shared with another version. A child that is left with a single key is
+    /// inlined back into this node, and one left with nothing but a `Buckets` child is replaced
+    /// by it, so the trie has the same shape as if the key had never been inserted.
+    fn remove<Q>(&mut self, key: &Q, hash: u64, depth: u32) -> Bucket<K,V>
+            where K: Borrow<Q>, Q: Eq + ?Sized {
+        let bit = 1 << split_hash(hash, depth);
+
+        if self.datamap & bit != 0 {
+            return match self.take(bit) { Data(b) => b, Child(_) => unreachable!() };
+        }
+
+        let i = self.node_index(bit);
+        let (removed, lone) = match *P::make_mut(&mut self.nodes[i]) {
+            Buckets(ref mut leaf) => {
+                let removed = leaf.remove(key).unwrap();
+                let lone = match leaf.buckets.len() {
+                    1 => Some(Data(leaf.buckets.pop().unwrap())),
+                    _ => None
+                };
+                (removed, lone)
+            }
+            Branches(ref mut node) => {
+                let removed = node.remove(key, hash, depth + 1);
+                let lone = match (node.data.len(), node.nodes.len()) {
+                    (1, 0) => Some(Data(node.data.pop().unwrap())),
+                    (0, 1) if matches!(*node.nodes[0], Buckets(..)) => {
+                        Some(Child(node.nodes.pop().unwrap()))
+                    }
+                    _ => None
+                };
+                (removed, lone)
+            }
+        };
+        self.size -= 1;
+        if let Some(slot) = lone {
+            self.replace(bit, slot);
+        }
+        removed
+    }
+
+    /// Merge two nodes at `depth` slot by slot, as `m` says
+    fn merge<B: Clone, C, M>(a: &Node<K,V,P>, b: &Node<K,B,P>, m: &mut M, depth: u32) -> Node<K,C,P>
+            where M: Merge<K,V,B,C,P> {
+        let mut node = Node::empty();
+        let mut bits = a.datamap | a.nodemap | b.datamap | b.nodemap;
+        while bits != 0 {
+            let bit = bits & bits.wrapping_neg();
+            bits &= bits - 1;
+            if let Some(slot) = Node::merge_slot(a.slot(bit), b.slot(bit), m, depth) {
+                node.put(bit, slot);
+            }
+        }
+        node
+    }
+
+    /// Merge what two nodes at `depth` hold in the same slot. A side that has the slot to itself
+    /// is handed to `m` whole, and so is a child that both sides share if `m` can tell what to do
+    /// with it.
+    fn merge_slot<B: Clone, C, M>(a: SlotRef<'_,K,V,P>, b: SlotRef<'_,K,B,P>, m: &mut M, depth: u32)
+                                  -> Option<Slot<K,C,P>> where M: Merge<K,V,B,C,P> {
+        match (a, b) {
+            (_, SlotRef::Empty) => return m.left(a, depth),
+            (SlotRef::Empty, _) => return m.right(b, depth),
+            (SlotRef::Child(x), SlotRef::Child(y)) => if let Some(slot) = m.shared(x, y) {
+                return slot;
+            },
+            _ => ()
+        }
+        match (a.single_hash(), b.single_hash()) {
+            (Some((hash, xs)), Some((b_hash, ys))) if hash == b_hash => {
+                let mut buckets = Vec::new();
+                for x in xs {
+                    let slot = match ys.iter().find(|y| x.key == y.key) {
+                        Some(y) => m.both(&x.key, &x.value, &y.value).map(|value| {
+                            Data(Bucket { hash, key: x.key.clone(), value })
+                        }),
+                        None => m.left(SlotRef::Data(x), depth + 1)
+                    };
+                    if let Some(Data(bucket)) = slot {
+                        buckets.push(bucket);
+                    }
+                }
+                for y in ys.iter().filter(|y| !xs.iter().any(|x| x.key == y.key)) {
+                    if let Some(Data(bucket)) = m.right(SlotRef::Data(y), depth + 1) {
+                        buckets.push(bucket);
+                    }
+                }
+                Slot::from_buckets(hash, buckets)
+            }
+            (Some((a_hash, _)), Some((b_hash, _))) => match (m.left(a, depth), m.right(b, depth)) {
+                (Some(x), Some(y)) => {
+                    let node = Node::join(x, a_hash, y, b_hash, depth + 1);
+                    Some(Child(P::new(Branches(node))))
+                }
+                (x, y) => x.or(y)
+            },
+            _ => {
+                let (x, y) = (a.to_node(depth + 1), b.to_node(depth + 1));
+                Node::merge(&x, &y, m, depth + 1).into_slot()
+            }
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, P: PointerKind> Node<K,V,P> {
+    /// Split a node at `depth` into one node with the entries for which `f` is true and one with
+    /// the rest
+    fn partition<F>(&self, f: &mut F, depth: u32) -> (Node<K,V,P>, Node<K,V,P>)
+            where F: FnMut(&K, &V) -> bool {
+        let (mut yes, mut no) = (Node::empty(), Node::empty());
+        let mut bits = self.datamap | self.nodemap;
+        while bits != 0 {
+            let bit = bits & bits.wrapping_neg();
+            bits &= bits - 1;
+            Node::partition_slot(self.slot(bit), bit, f, depth, &mut yes, &mut no);
+        }
+        (yes, no)
+    }
+
+    /// Split one slot of a node at `depth` by `f`, into the same slot of `yes` and `no`. A child
+    /// whose entries all go the same way is shared instead of being copied.
+    fn partition_slot<F>(slot: SlotRef<'_,K,V,P>, bit: Bitmap, f: &mut F, depth: u32,
+                         yes: &mut Node<K,V,P>, no: &mut Node<K,V,P>)
+            where F: FnMut(&K, &V) -> bool {
+        let split = match slot {
+            SlotRef::Empty => return,
+            SlotRef::Data(b) => {
+                let side = if f(&b.key, &b.value) { yes } else { no };
+                side.put(bit, Data(b.clone()));
+                return;
+            }
+            SlotRef::Child(child) => match **child {
+                Buckets(ref leaf) => {
+                    let (ys, ns): (Vec<&Bucket<K,V>>, Vec<_>) = leaf.buckets.iter()
+                        .partition(|b| f(&b.key, &b.value));
+                    let ys = Slot::from_buckets(leaf.hash, ys.into_iter().cloned().collect());
+                    let ns = Slot::from_buckets(leaf.hash, ns.into_iter().cloned().collect());
+                    (ys, ns)
+                }
+                Branches(ref node) => {
+                    let (ys, ns) = node.partition(f, depth + 1);
+                    (ys.into_slot(), ns.into_slot())
+                }
+            }
+        };
+        match split {
+            (Some(_), None) => yes.put(bit, slot.to_slot().unwrap()),
+            (None, Some(_)) => no.put(bit, slot.to_slot().unwrap()),
+            (ys, ns) => {
+                if let Some(ys) = ys {
+                    yes.put(bit, ys);
+                }
+                if let Some(ns) = ns {
+                    no.put(bit, ns);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Clone, V, P: PointerKind> Node<K,V,P> {
+    /// Build a node with the same bitsets and hashes, and `f` applied to each entry, stopping at
+    /// the first error
+    fn try_map<W, E, F>(&self, f: &mut F) -> Result<Node<K,W,P>, E>
+            where F: FnMut(&K, &V) -> Result<W, E> {
+        let data = self.data.iter().map(|b| b.try_map(f)).collect::<Result<_, _>>()?;
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        for child in &self.nodes {
+            let child = match **child {
+                Buckets(ref leaf) => {
+                    let buckets: Result<_, _> = leaf.buckets.iter().map(|b| b.try_map(f)).collect();
+                    Buckets(Collision { hash: leaf.hash, buckets: buckets? })
+                }
+                Branches(ref node) => Branches(node.try_map(f)?)
+            };
+            nodes.push(P::new(child));
+        }
+        Ok(Node { datamap: self.datamap, nodemap: self.nodemap, size: self.size, data, nodes })
+    }
+
+    /// Build a node at `depth` with `f` applied to each entry, leaving out the keys it returns
+    /// `None` for
+    fn filter_map<U, F>(&self, f: &mut F, depth: u32) -> Node<K,U,P>
+            where F: FnMut(&K, &V) -> Option<U> {
+        let mut node = Node::empty();
+        let mut bits = self.datamap | self.nodemap;
+        while bits != 0 {
+            let bit = bits & bits.wrapping_neg();
+            bits &= bits - 1;
+            if let Some(slot) = Node::filter_map_slot(self.slot(bit), f, depth) {
+                node.put(bit, slot);
+            }
+        }
+        node
+    }
+
+    /// Apply `f` to the entries of one slot of a node at `depth`
+    fn filter_map_slot<U, F>(slot: SlotRef<'_,K,V,P>, f: &mut F, depth: u32) -> Option<Slot<K,U,P>>
+            where F: FnMut(&K, &V) -> Option<U> {
+        match slot {
+            SlotRef::Empty => None,
+            SlotRef::Data(b) => f(&b.key, &b.value).map(|value| {
+                Data(Bucket { hash: b.hash, key: b.key.clone(), value })
+            }),
+            SlotRef::Child(child) => match **child {
+                Buckets(ref leaf) => {
+                    let buckets = leaf.buckets.iter().filter_map(|b| {
+                        let value = f(&b.key, &b.value)?;
+                        Some(Bucket { hash: b.hash, key: b.key.clone(), value })
+                    }).collect();
+                    Slot::from_buckets(leaf.hash, buckets)
+                }
+                Branches(ref node) => node.filter_map(f, depth + 1).into_slot()
+            }
+        }
+    }
+}
+
+impl<K: Eq, V, P: PointerKind> Node<K,V,P> {
+    /// Walk the nodes selected by successive chunks of the hash, starting from this node at
+    /// `depth`, down to the slot that would hold `key`, and return its bucket if the key is there
+    fn find<Q>(&self, key: &Q, hash: u64, depth: u32) -> Option<&Bucket<K,V>>
+            where K: Borrow<Q>, Q: Eq + ?Sized {
+        let mut current: &Node<K,V,P> = self;
+        let mut depth = depth;
+
+        loop {
+            let bit = 1 << split_hash(hash, depth);
+            if current.datamap & bit != 0 {
+                let b = &current.data[current.data_index(bit)];
+                return match b.hash == hash && key.eq(b.key.borrow()) {
+                    true => Some(b),
+                    false => None
+                };
+            }
+            if current.nodemap & bit == 0 {
+                return None;
+            }
+            match *current.nodes[current.node_index(bit)] {
+                Buckets (ref leaf) => return match leaf.hash == hash {
+                    true => leaf.find(key),
+                    false => None
+                },
+                Branches (ref node) => {
+                    depth += 1;
+                    current = node;
+                }
+            }
+        }
+    }
+
+    /// Return true if two nodes hold the same entries. Each set of keys has only one shape, so
+    /// equal nodes have equal bitsets and can be compared slot by slot, skipping the children
+    /// they share.
+    fn equal(&self, other: &Node<K,V,P>) -> bool where V: PartialEq {
+        if self.size != other.size || self.datamap != other.datamap
+                || self.nodemap != other.nodemap {
+            return false;
+        }
+        let same_data = self.data.iter().zip(&other.data).all(|(x, y)| {
+            x.hash == y.hash && x.key == y.key && x.value == y.value
+        });
+        same_data && self.nodes.iter().zip(&other.nodes).all(|(x, y)| {
+            P::ptr_eq(x, y) || match (&**x, &**y) {
+                (Buckets(x), Buckets(y)) => {
+                    let same = |b: &Bucket<K,V>| {
+                        y.find(&b.key).is_some_and(|c| b.value == c.value)
+                    };
+                    x.hash == y.hash && x.buckets.len() == y.buckets.len()
+                        && x.buckets.iter().all(same)
+                }
+                (Branches(x), Branches(y)) => x.equal(y),
+                _ => false
+            }
+        })
+    }
+
+    /// Return true if every key in the node at `depth` is also in `other`
+    fn keys_subset(&self, other: &Node<K,V,P>, depth: u32) -> bool {
+        let mut bits = self.datamap | self.nodemap;
+        if self.size > other.size || bits & !(other.datamap | other.nodemap) != 0 {
+            return false;
+        }
+        while bits != 0 {
+            let bit = bits & bits.wrapping_neg();
+            bits &= bits - 1;
+            if !Node::slot_subset(self.slot(bit), other.slot(bit), depth) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return true if every key in one slot of a node at `depth` is in the same slot of another
+    fn slot_subset(a: SlotRef<'_,K,V,P>, b: SlotRef<'_,K,V,P>, depth: u32) -> bool {
+        match (a, b) {
+            (SlotRef::Empty, _) => true,
+            (_, SlotRef::Empty) => false,
+            (SlotRef::Child(x), SlotRef::Child(y)) if P::ptr_eq(x, y) => true,
+            _ => match (a.single_hash(), b.single_hash()) {
+                (Some((hash, xs)), Some((b_hash, ys))) => {
+                    hash == b_hash && xs.iter().all(|x| ys.iter().any(|y| x.key == y.key))
+                }
+                (Some((hash, xs)), None) => {
+                    let node = b.branch().unwrap();
+                    xs.iter().all(|x| node.find(&x.key, hash, depth + 1).is_some())
+                }
+                // a `Branches` child has keys with different hashes, which one hash can't cover
+                (None, Some(_)) => false,
+                (None, None) => a.branch().unwrap().keys_subset(b.branch().unwrap(), depth + 1)
+            }
+        }
+    }
+
+    /// Return true if no key is in both the node at `depth` and `other`
+    fn keys_disjoint(&self, other: &Node<K,V,P>, depth: u32) -> bool {
+        let mut bits = (self.datamap | self.nodemap) & (other.datamap | other.nodemap);
+        while bits != 0 {
+            let bit = bits & bits.wrapping_neg();
+            bits &= bits - 1;
+            if !Node::slot_disjoint(self.slot(bit), other.slot(bit), depth) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Return true if no key is in both one slot of a node at `depth` and the same slot of another
+    fn slot_disjoint(a: SlotRef<'_,K,V,P>, b: SlotRef<'_,K,V,P>, depth: u32) -> bool {
+        match (a, b) {
+            (SlotRef::Empty, _) | (_, SlotRef::Empty) => true,
+            (SlotRef::Child(x), SlotRef::Child(y)) if P::ptr_eq(x, y) => false,
+            _ => match (a.single_hash(), b.single_hash()) {
+                (Some((hash, xs)), Some((b_hash, ys))) => {
+                    hash != b_hash || !xs.iter().any(|x| ys.iter().any(|y| x.key == y.key))
+                }
+                (Some((hash, xs)), None) => {
+                    let node = b.branch().unwrap();
+                    xs.iter().all(|x| node.find(&x.key, hash, depth + 1).is_none())
+                }
+                (None, Some((hash, ys))) => {
+                    let node = a.branch().unwrap();
+                    ys.iter().all(|y| node.find(&y.key, hash, depth + 1).is_none())
+                }
+                (None, None) => a.branch().unwrap().keys_disjoint(b.branch().unwrap(), depth + 1)
+            }
+        }
+    }
+}
+
+
+
+/// A `BuildHasher` with a fixed seed, for containers that must iterate in the same order in
+/// every run and on every target, as golden-file tests and reproducible builds need.
+///
+/// The hasher mixes what it is given with a function defined here rather than in the standard
+/// library, and reads integers as little-endian, and `usize` and `isize` as 64 bits wide. What a
+/// key feeds it comes from the key's `Hash` impl, though, and the standard library doesn't promise
+/// to keep its impls the same between Rust versions. Only a key type whose own `Hash` impl calls
+/// the hasher's `write` methods directly has hashes that never change. Keys with equal hashes
+/// are the exception to the fixed order, see the module docs.
+///
+/// Since anyone can compute its hashes, it doesn't protect against keys that were chosen to
+/// collide; keep the random default for keys that come from untrusted input. Maps that are each
+/// created with a `FixedState` hash alike, but they only merge without rehashing if they come
+/// from one `HasherFamily`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedState {
+    seed: u64
+}
+
+impl FixedState {
+    /// Create a hasher builder with the seed 0, like `FixedState::default()`
+    pub const fn new() -> FixedState {
+        FixedState { seed: 0 }
+    }
+
+    /// Create a hasher builder with another seed. Each seed gives another fixed order.
+    pub const fn with_seed(seed: u64) -> FixedState {
+        FixedState { seed }
+    }
+}
+
+impl BuildHasher for FixedState {
+    type Hasher = FixedHasher;
+
+    fn build_hasher(&self) -> FixedHasher {
+        FixedHasher { state: self.seed }
+    }
+}
+
+/// The hasher built by `FixedState`
+#[derive(Clone, Debug)]
+pub struct FixedHasher {
+    state: u64
+}
+
+impl FixedHasher {
+    fn add(&mut self, word: u64) {
+        self.state = (self.state.rotate_left(5) ^ word).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+}
+
+impl Hasher for FixedHasher {
+    /// Mix the bytes in 8 at a time. The last few are padded, with their count in the top byte,
+    /// so that byte strings that differ only in trailing zeros hash differently.
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut tail = [0; 8];
+            tail[..rest.len()].copy_from_slice(rest);
+            self.add(u64::from_le_bytes(tail) | (rest.len() as u64) << 56);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) { self.add(i as u64) }
+
+    fn write_u16(&mut self, i: u16) { self.add(i as u64) }
+
+    fn write_u32(&mut self, i: u32) { self.add(i as u64) }
+
+    fn write_u64(&mut self, i: u64) { self.add(i) }
+
+    fn write_u128(&mut self, i: u128) {
+        self.add(i as u64);
+        self.add((i >> 64) as u64);
+    }
+
+    fn write_usize(&mut self, i: usize) { self.add(i as u64) }
+
+    fn write_isize(&mut self, i: isize) { self.add(i as i64 as u64) }
+
+    /// Finish with the MurmurHash3 finalizer, so that every bit of the hash depends on every
+    /// bit of the state. The trie takes its first levels from the low bits.
+    fn finish(&self) -> u64 {
+        let mut h = self.state;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+
+
+/// One hasher shared by all the maps and sets created from it.
+///
+/// Containers only know that they hash their keys alike if they share the same hasher instance;
+/// two hashers that are equal but were created separately count as different, even ones with a
+/// fixed seed like `FixedState`. So to union, intersect or compare containers that are built
+/// separately without rehashing either one, create them all from one family, or from the
+/// `family()` of one of them.
+pub struct HasherFamily<S = RandomState,P: PointerKind = RcPointer> {
+    hasher: P::Pointer<S>
+}
+
+/// A `HasherFamily` for maps and sets that can be shared between threads
+pub type SyncHasherFamily<S = RandomState> = HasherFamily<S,ArcPointer>;
+
+impl HasherFamily {
+    /// Create a family with a new random hasher
+    pub fn new() -> HasherFamily {
+        HasherFamily::default()
+    }
+}
+
+impl<S> HasherFamily<S> {
+    /// Create a family whose containers hash their keys with `hasher`
+    pub fn with_hasher(hasher: S) -> HasherFamily<S> {
+        HasherFamily { hasher: Rc::new(hasher) }
+    }
+}
+
+impl SyncHasherFamily {
+    /// Create a family with a new random hasher, for containers that can be shared between
+    /// threads
+    pub fn new_sync() -> SyncHasherFamily {
+        HasherFamily::default()
+    }
+}
+
+impl<S> SyncHasherFamily<S> {
+    /// Create a family whose containers can be shared between threads and hash their keys with
+    /// `hasher`
+    pub fn with_hasher_sync(hasher: S) -> SyncHasherFamily<S> {
+        HasherFamily { hasher: Arc::new(hasher) }
+    }
+}
+
+impl<S,P: PointerKind> HasherFamily<S,P> {
+    /// Return a reference to the family's hasher
+    pub fn hasher(&self) -> &S { &self.hasher }
+
+    /// Create an empty map in the family
+    pub fn map<K,V>(&self) -> HashMap<K,V,S,P> {
+        HashMap { size: 0, map: P::new(Node::empty()), hasher: self.hasher.clone() }
+    }
+
+    /// Create an empty set in the family
+    pub fn set<T>(&self) -> HashSet<T,S,P> {
+        HashSet { map: self.map() }
+    }
+
+    /// Create an empty transient map in the family
+    pub fn transient_map<K,V>(&self) -> TransientHashMap<K,V,S,P> {
+        self.map().transient()
+    }
+
+    /// Create an empty transient set in the family
+    pub fn transient_set<T>(&self) -> TransientHashSet<T,S,P> {
+        self.set().transient()
+    }
+
+    /// Return true if the two families share their hasher
+    pub fn ptr_eq(&self, other: &HasherFamily<S,P>) -> bool {
+        P::ptr_eq(&self.hasher, &other.hasher)
+    }
+}
+
+impl<S: BuildHasher,P: PointerKind> HasherFamily<S,P> {
+    /// Create a map in the family from the key-value pairs of `iter`
+    pub fn map_from<K,V,I>(&self, iter: I) -> HashMap<K,V,S,P>
+            where K: Hash + Eq + Clone, V: Clone, I: IntoIterator<Item = (K,V)> {
+        let mut map = self.transient_map();
+        map.extend(iter);
+        map.persistent()
+    }
+
+    /// Create a set in the family from the values of `iter`
+    pub fn set_from<T,I>(&self, iter: I) -> HashSet<T,S,P>
+            where T: Hash + Eq + Clone, I: IntoIterator<Item = T> {
+        let mut set = self.transient_set();
+        set.extend(iter);
+        set.persistent()
+    }
+}
+
+impl<S,P: PointerKind> Clone for HasherFamily<S,P> {
+    fn clone(&self) -> HasherFamily<S,P> {
+        HasherFamily { hasher: self.hasher.clone() }
+    }
+}
+
+impl<S: Default,P: PointerKind> Default for HasherFamily<S,P> {
+    fn default() -> HasherFamily<S,P> {
+        HasherFamily { hasher: P::new(S::default()) }
+    }
+}
+
+
+
+/// A persistent map from keys of type `K` to values of type `V`, hashing its keys with `S`.
+///
+/// Every version of a map that is derived from another one, by `insert`, a merge, or any other
+/// operation, keeps the other's hasher. Operations on two maps compare their tries directly when
+/// the maps share a hasher, and otherwise rehash one side first, or look its keys up one by one.
+/// Sharing a hasher means sharing the same instance, not having equal ones, so a `HasherFamily`
+/// is the only way to build separate maps that don't need the rehash.
+pub struct HashMap<K,V,S = RandomState,P: PointerKind = RcPointer> {
+    size: usize,
+    map: P::Pointer<Node<K,V,P>>,
+    hasher: P::Pointer<S>
+}
+
+/// A `HashMap` whose versions can be sent to and shared between threads
+pub type SyncHashMap<K,V,S = RandomState> = HashMap<K,V,S,ArcPointer>;
+
+impl<K,V> HashMap<K,V> {
+    /// Create an empty map, with new random keys for its hasher
+    pub fn new() -> HashMap<K,V> {
+        HashMap::default()
+    }
+}
+
+impl<K,V,S> HashMap<K,V,S> {
+    /// Create an empty map that hashes its keys with `hasher`
+    pub fn with_hasher(hasher: S) -> HashMap<K,V,S> {
+        HashMap { size: 0, map: Rc::new(Node::empty()), hasher: Rc::new(hasher) }
+    }
+}
+
+impl<K,V> SyncHashMap<K,V> {
+    /// Create an empty map that can be shared between threads
+    pub fn new_sync() -> SyncHashMap<K,V> {
+        HashMap::default()
+    }
+}
+
+impl<K,V,S> SyncHashMap<K,V,S> {
+    /// Create an empty map that can be shared between threads and hashes its keys with `hasher`
+    pub fn with_hasher_sync(hasher: S) -> SyncHashMap<K,V,S> {
+        HashMap { size: 0, map: Arc::new(Node::empty()), hasher: Arc::new(hasher) }
+    }
+}
+
+impl<K,V,S,P: PointerKind> HashMap<K,V,S,P> {
+    /// Return the number of elements in the map
+    pub fn len(&self) -> usize { self.size }
+
+    /// Return a reference to the map's hasher
+    pub fn hasher(&self) -> &S { &self.hasher }
+
+    /// Return the family of the map's hasher, to create other maps and sets that share it
+    pub fn family(&self) -> HasherFamily<S,P> {
+        HasherFamily { hasher: self.hasher.clone() }
+    }
+
+    /// Return true if the map contains no elements
+    pub fn is_empty(&self) -> bool { self.size == 0 }
+
+    /// Return true if the two maps share their root node, which makes them the same version.
+    /// Maps for which this is true are always equal, but equal maps need not share their root.
+    pub fn ptr_eq(&self, other: &HashMap<K,V,S,P>) -> bool { P::ptr_eq(&self.map, &other.map) }
+
+    /// An iterator visiting all key-value pairs, in the order of the trie
+    pub fn iter(&self) -> Iter<'_,K,V,P> {
+        let mut nodes = Vec::with_capacity(MAX_DEPTH as usize + 1);
+        nodes.push(self.map.nodes.iter());
+        Iter { nodes, data: self.map.data.iter(), remaining: self.size }
+    }
+
+    /// An iterator visiting all keys, in the order of the trie
+    pub fn keys(&self) -> Keys<'_,K,V,P> {
+        Keys { iter: self.iter() }
+    }
+
+    /// An iterator visiting all values, in the order of the trie
+    pub fn values(&self) -> Values<'_,K,V,P> {
+        Values { iter: self.iter() }
+    }
+
+    /// Return a map with the same hasher as `self`, and `root` as its root node
+    fn with_root<W>(&self, root: Node<K,W,P>) -> HashMap<K,W,S,P> {
+        HashMap { size: root.size, map: P::new(root), hasher: self.hasher.clone() }
+    }
+
+    /// Return true if `other` has the same hasher as `self`, so that each key has the same place
+    /// in both tries. Hashers aren't compared by value, since most of them, like `RandomState`,
+    /// can't be, so this is only true for maps whose hasher came from the same instance.
+    fn same_hasher<W>(&self, other: &HashMap<K,W,S,P>) -> bool {
+        P::ptr_eq(&self.hasher, &other.hasher)
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher, P: PointerKind> HashMap<K,V,S,P> {
+    /// Return a reference to the value corresponding to the key.
+    ///
+    /// The key may be any borrowed form of the map's key type, but `Hash` and `Eq` on the
+    /// borrowed form *must* match those for the key type.
+    pub fn get<Q>(&self, k: &Q) -> Option<&V> where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.find(k, self.hasher.hash_one(k), 0).map(|b| &b.value)
+    }
+
+    /// Return references to the stored key and the value corresponding to the key
+    pub fn get_key_value<Q>(&self, k: &Q) -> Option<(&K, &V)>
+            where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.find(k, self.hasher.hash_one(k), 0).map(|b| (&b.key, &b.value))
+    }
+
+    /// Return true if the map contains a value for the key
+    pub fn contains_key<Q>(&self, k: &Q) -> bool where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.find(k, self.hasher.hash_one(k), 0).is_some()
+    }
+
+    /// Return true if every key of `self` is also a key of `other`. The two tries are walked
+    /// together, and a subtree that both share is not looked into.
+    fn keys_subset(&self, other: &HashMap<K,V,S,P>) -> bool {
+        if !self.same_hasher(other) {
+            return self.size <= other.size && self.keys().all(|k| other.contains_key(k));
+        }
+        P::ptr_eq(&self.map, &other.map) || self.map.keys_subset(&other.map, 0)
+    }
+
+    /// Return true if no key is in both `self` and `other`
+    fn keys_disjoint(&self, other: &HashMap<K,V,S,P>) -> bool {
+        if !self.same_hasher(other) {
+            return self.keys().all(|k| !other.contains_key(k));
+        }
+        match P::ptr_eq(&self.map, &other.map) {
+            true => self.is_empty(),
+            false => self.map.keys_disjoint(&other.map, 0)
+        }
+    }
+
+    /// An iterator over the changes that turn `self` into `other`, in no particular order.
+    ///
+    /// The two tries are walked together and the subtrees they share are skipped, so the diff
+    /// between two versions of a map costs time proportional to how much they differ.
+    pub fn diff<'a>(&'a self, other: &'a HashMap<K,V,S,P>) -> Diff<'a,K,V,S,P> where V: PartialEq {
+        let mut diff = Diff {
+            stack: Vec::with_capacity(MAX_DEPTH as usize + 1),
+            removed: Iter::slot(SlotRef::Empty),
+            added: Iter::slot(SlotRef::Empty),
+            changed: Vec::new(),
+            old: self,
+            new: other,
+            lookup: !self.same_hasher(other)
+        };
+        if diff.lookup {
+            diff.removed = self.iter();
+            diff.added = other.iter();
+        } else if !P::ptr_eq(&self.map, &other.map) {
+            diff.push(Side::Branch(&self.map), Side::Branch(&other.map), 0);
+        }
+        diff
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, S: BuildHasher, P: PointerKind> HashMap<K,V,S,P> {
+    /// Return a new map that also maps `k` to `v`, replacing any value `k` had before.
+    /// Only the nodes on the path to `k` are copied; the rest is shared with `self`.
+    pub fn insert(&self, k: K, v: V) -> HashMap<K,V,S,P> {
+        let mut map = self.transient();
+        map.insert(k, v);
+        map.persistent()
+    }
+
+    /// Return a new map without `k`. If `k` is not in the map this is just a clone of `self`.
+    pub fn remove<Q>(&self, k: &Q) -> HashMap<K,V,S,P> where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let mut map = self.transient();
+        map.remove(k);
+        map.persistent()
+    }
+
+    /// Look up `key` for reading and then changing its entry. The key is hashed once and the
+    /// nodes on its path are remembered, so making the new version of the map from the entry
+    /// doesn't walk the trie again.
+    pub fn entry(&self, key: K) -> Entry<'_,K,V,S,P> {
+        let path = Path::new(self, self.hasher.hash_one(&key));
+        match path.position(&key) {
+            Some(index) => Entry::Occupied(OccupiedEntry { path, index }),
+            None => Entry::Vacant(VacantEntry { path, key })
+        }
+    }
+
+    /// Return a new map where `k` has the value `f(current)`, or isn't there if that is `None`.
+    /// `current` is the value `k` has now, if any. The closure can insert, change or remove the
+    /// key in one lookup.
+    ///
+    /// Like `adjust`, `update` and `insert_with`, this returns a map that shares its root with
+    /// `self`, so that `HashMap::ptr_eq` is true for the two, if nothing changed.
+    pub fn alter<F>(&self, k: K, f: F) -> HashMap<K,V,S,P>
+            where V: PartialEq, F: FnOnce(Option<&V>) -> Option<V> {
+        match self.entry(k) {
+            Entry::Occupied(entry) => entry.update(|v| f(Some(v))),
+            Entry::Vacant(entry) => match f(None) {
+                Some(v) => entry.insert(v),
+                None => self.clone()
+            }
+        }
+    }
+
+    /// Return a new map where the value of `k`, if it is there, is changed to `f(value)`
+    pub fn adjust<Q, F>(&self, k: &Q, f: F) -> HashMap<K,V,S,P>
+            where K: Borrow<Q>, Q: Hash + Eq + ?Sized, V: PartialEq, F: FnOnce(&V) -> V {
+        self.update(k, |v| Some(f(v)))
+    }
+
+    /// Return a new map where the value of `k`, if it is there, is changed to `f(value)`, or
+    /// removed if that is `None`
+    pub fn update<Q, F>(&self, k: &Q, f: F) -> HashMap<K,V,S,P>
+            where K: Borrow<Q>, Q: Hash + Eq + ?Sized, V: PartialEq, F: FnOnce(&V) -> Option<V> {
+        let path = Path::new(self, self.hasher.hash_one(k));
+        match path.position(k) {
+            Some(index) => OccupiedEntry { path, index }.update(f),
+            None => self.clone()
+        }
+    }
+
+    /// Return a new map where `k` has the value `v` if it wasn't there, or `f(v, old_value)` if
+    /// it was
+    pub fn insert_with<F>(&self, k: K, v: V, f: F) -> HashMap<K,V,S,P>
+            where V: PartialEq, F: FnOnce(V, &V) -> V {
+        match self.entry(k) {
+            Entry::Occupied(entry) => entry.update(|old| Some(f(v, old))),
+            Entry::Vacant(entry) => entry.insert(v)
+        }
+    }
+
+    /// Return a map with the entries of both maps. For a key that is in both, the value is
+    /// `f(key, self_value, other_value)`.
+    ///
+    /// The two tries are merged node by node, and a subtree that only one of the maps has goes
+    /// into the result without being copied. If `other` has a different hasher, it is rehashed
+    /// with the hasher of `self` first, as for every other merge.
+    pub fn union_with<F>(&self, other: &HashMap<K,V,S,P>, f: F) -> HashMap<K,V,S,P>
+            where F: FnMut(&K, &V, &V) -> V {
+        self.merge_by(other, &mut UnionWith(f))
+    }
+
+    /// Return a map with the keys that are in both maps, mapped to
+    /// `f(key, self_value, other_value)`
+    pub fn intersection_with<W, U, F>(&self, other: &HashMap<K,W,S,P>, f: F) -> HashMap<K,U,S,P>
+            where W: Clone, F: FnMut(&K, &V, &W) -> U {
+        self.merge_by(other, &mut IntersectionWith(f))
+    }
+
+    /// Merge two maps in one pass over both tries. The value for a key that is only in `self`
+    /// is `left(key, value)`, the value for a key in both is `both(key, self_value, other_value)`,
+    /// and the value for a key only in `other` is `right(key, value)`; a key is left out of the
+    /// result wherever its function returns `None`.
+    pub fn merge<W, U, F, G, H>(&self, other: &HashMap<K,W,S,P>, left: F, both: G, right: H)
+                                -> HashMap<K,U,S,P>
+            where W: Clone,
+                  F: FnMut(&K, &V) -> Option<U>,
+                  G: FnMut(&K, &V, &W) -> Option<U>,
+                  H: FnMut(&K, &W) -> Option<U> {
+        self.merge_by(other, &mut MergeWith { left, both, right })
+    }
+
+    /// Return the map with only the keys that are in `keys`.
+    ///
+    /// Like `without_keys`, this merges the trie of the map with the trie of the set node by
+    /// node, which is why the set must share the map's pointer kind. A subtree of the map that
+    /// the set has no keys in is dropped, or kept without being copied, as a whole.
+    pub fn restrict_keys(&self, keys: &HashSet<K,S,P>) -> HashMap<K,V,S,P> {
+        self.merge_by(&keys.map, &mut Restrict { inside: true })
+    }
+
+    /// Return the map without the keys that are in `keys`
+    pub fn without_keys(&self, keys: &HashSet<K,S,P>) -> HashMap<K,V,S,P> {
+        self.merge_by(&keys.map, &mut Restrict { inside: false })
+    }
+
+    /// Merge the tries of two maps node by node, as `m` says. The result has the hasher of
+    /// `self`.
+    fn merge_by<W: Clone, U, M>(&self, other: &HashMap<K,W,S,P>, m: &mut M) -> HashMap<K,U,S,P>
+            where M: Merge<K,V,W,U,P> {
+        let other = self.rehashed(other);
+        let map = Node::merge(&self.map, &other.map, m, 0);
+        self.with_root(map)
+    }
+
+    /// Merge the tries of two maps node by node, keeping the keys that `keep` asks for with the
+    /// values from `self`
+    fn merge_keys(&self, other: &HashMap<K,V,S,P>, mut keep: Keep) -> HashMap<K,V,S,P> {
+        if P::ptr_eq(&self.map, &other.map) {
+            return if keep.both { self.clone() } else { self.with_root(Node::empty()) };
+        }
+        self.merge_by(other, &mut keep)
+    }
+
+    /// Return `other` with its keys placed by the hasher of `self`, so that the two tries can
+    /// be merged node by node. This is `other` itself if the maps share a hasher, and a copy of
+    /// it built from scratch if not.
+    fn rehashed<'a, W: Clone>(&self, other: &'a HashMap<K,W,S,P>) -> Cow<'a, HashMap<K,W,S,P>> {
+        if self.same_hasher(other) {
+            return Cow::Borrowed(other);
+        }
+        let mut map = self.with_root(Node::empty()).transient();
+        map.extend(other.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Cow::Owned(map.persistent())
+    }
+}
+
+impl<K: Clone, V, S, P: PointerKind> HashMap<K,V,S,P> {
+    /// Return a map with the same keys and `f` applied to each value.
+    ///
+    /// Like the other methods that map or filter values, this copies the trie node by node and
+    /// keeps the stored hashes, so no key is hashed or inserted again.
+    pub fn map_values<W, F>(&self, mut f: F) -> HashMap<K,W,S,P> where F: FnMut(&V) -> W {
+        self.map_values_with_key(|_, v| f(v))
+    }
+
+    /// Return the keys of the map as a set. The set's trie has the same shape and stored hashes
+    /// as the map's, so it is built in one pass over the map without hashing any key.
+    pub fn key_set(&self) -> HashSet<K,S,P> {
+        HashSet { map: self.map_values(|_| ()) }
+    }
+
+    /// Return a map with the same keys and `f(key, value)` as the value of each key
+    pub fn map_values_with_key<W, F>(&self, mut f: F) -> HashMap<K,W,S,P>
+            where F: FnMut(&K, &V) -> W {
+        match self.try_map(|k, v| Ok::<W, Infallible>(f(k, v))) {
+            Ok(map) => map,
+            Err(never) => match never {}
+        }
+    }
+
+    /// Return a map with `f(key, value)` as the value of each key, or the first error `f`
+    /// returns
+    pub fn try_map<W, E, F>(&self, mut f: F) -> Result<HashMap<K,W,S,P>, E>
+            where F: FnMut(&K, &V) -> Result<W, E> {
+        Ok(self.with_root(self.map.try_map(&mut f)?))
+    }
+
+    /// Return a map with `f(key, value)` as the value of each key, leaving out the keys for
+    /// which it returns `None`. Nodes that lose keys are compacted, so the result has the same
+    /// shape as if it was built from scratch.
+    pub fn filter_map<W, F>(&self, mut f: F) -> HashMap<K,W,S,P>
+            where F: FnMut(&K, &V) -> Option<W> {
+        self.with_root(self.map.filter_map(&mut f, 0))
+    }
+
+    /// Return a map with only the entries for which `f(key, value)` is true
+    pub fn filter<F>(&self, mut f: F) -> HashMap<K,V,S,P>
+            where V: Clone, F: FnMut(&K, &V) -> bool {
+        self.filter_map(|k, v| if f(k, v) { Some(v.clone()) } else { None })
+    }
+
+    /// Keep only the entries for which `f(key, value)` is true
+    pub fn retain<F>(&mut self, f: F) where V: Clone, F: FnMut(&K, &V) -> bool {
+        *self = self.filter(f);
+    }
+
+    /// Split the map into one with the entries for which `f(key, value)` is true and one with
+    /// the rest, in one pass over the trie. A subtree whose entries all go to the same side is
+    /// shared with that side instead of being copied, and nodes that lose keys are compacted.
+    pub fn partition<F>(&self, mut f: F) -> (Self, Self)
+            where V: Clone, F: FnMut(&K, &V) -> bool {
+        let (yes, no) = self.map.partition(&mut f, 0);
+        match (yes.size, no.size) {
+            (_, 0) => (self.clone(), self.with_root(Node::empty())),
+            (0, _) => (self.with_root(Node::empty()), self.clone()),
+            _ => (self.with_root(yes), self.with_root(no))
+        }
+    }
+
+    /// Remove the entries for which `f(key, value)` is true and return them as a new map
+    pub fn split_off_by<F>(&mut self, f: F) -> HashMap<K,V,S,P>
+            where V: Clone, F: FnMut(&K, &V) -> bool {
+        let (yes, no) = self.partition(f);
+        *self = no;
+        yes
+    }
+}
+
+impl<K,V,S,P: PointerKind> HashMap<K,V,S,P> {
+    /// Return a transient version of the map for making many changes in a row. It starts out
+    /// sharing every node with `self`, which is left unchanged.
+    pub fn transient(&self) -> TransientHashMap<K,V,S,P> {
+        TransientHashMap { size: self.size, map: self.map.clone(), hasher: self.hasher.clone() }
+    }
+}
+
+impl<K,V,S,P: PointerKind> Clone for HashMap<K,V,S,P> {
+    /// Return a copy of the map that shares all of its nodes with the original
+    fn clone(&self) -> HashMap<K,V,S,P> {
+        HashMap { size: self.size, map: self.map.clone(), hasher: self.hasher.clone() }
+    }
+}
+
+impl<K: Debug, V: Debug, S, P: PointerKind> Debug for HashMap<K,V,S,P> {
+    /// Format the entries in the order of the trie, which is the same every time with
+    /// `FixedState`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K,V,S: Default,P: PointerKind> Default for HashMap<K,V,S,P> {
+    fn default() -> HashMap<K,V,S,P> {
+        HashMap { size: 0, map: P::new(Node::empty()), hasher: P::new(S::default()) }
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher, P: PointerKind> PartialEq for HashMap<K,V,S,P> {
+    /// Compare the tries of the two maps node by node. Nodes that both maps share are equal
+    /// without being looked into, so comparing two versions of a map costs time proportional
+    /// to how much they differ.
+    fn eq(&self, other: &HashMap<K,V,S,P>) -> bool {
+        if !self.same_hasher(other) {
+            return self.size == other.size && self.iter().all(|(k, v)| other.get(k) == Some(v));
+        }
+        P::ptr_eq(&self.map, &other.map) || (self.size == other.size && self.map.equal(&other.map))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, S: BuildHasher, P: PointerKind> Eq for HashMap<K,V,S,P> {}
+
+impl<'a, K, V, S, P: PointerKind> IntoIterator for &'a HashMap<K,V,S,P> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a,K,V,P>;
+
+    fn into_iter(self) -> Iter<'a,K,V,P> { self.iter() }
+}
+
+impl<K,V,S,P: PointerKind> FromIterator<(K,V)> for HashMap<K,V,S,P>
+        where K: Hash + Eq + Clone, V: Clone, S: BuildHasher + Default {
+    fn from_iter<I: IntoIterator<Item = (K,V)>>(iter: I) -> HashMap<K,V,S,P> {
+        let mut map = TransientHashMap::default();
+        map.extend(iter);
+        map.persistent()
+    }
+}
+
+impl<K,V,S,P: PointerKind> Extend<(K,V)> for HashMap<K,V,S,P>
+        where K: Hash + Eq + Clone, V: Clone, S: BuildHasher {
+    fn extend<I: IntoIterator<Item = (K,V)>>(&mut self, iter: I) {
+        // take the root out of `self`, so that the transient can change it in place. The size
+        // goes with it, so that `self` is a valid empty map if `iter` panics.
+        let root = std::mem::replace(&mut self.map, P::new(Node::empty()));
+        let size = std::mem::take(&mut self.size);
+        let mut map = TransientHashMap { size, map: root, hasher: self.hasher.clone() };
+        map.extend(iter);
+        *self = map.persistent();
+    }
+}
+
+
+
+/// A view into one key of a `HashMap`, returned by `HashMap::entry`
+pub enum Entry<'a,K,V,S = RandomState,P: PointerKind = RcPointer> {
+    /// The key is in the map
+    Occupied (OccupiedEntry<'a,K,V,S,P>),
+    /// The key is not in the map
+    Vacant (VacantEntry<'a,K,V,S,P>)
+}
+
+/// An entry for a key that is in the map
+pub struct OccupiedEntry<'a,K,V,S = RandomState,P: PointerKind = RcPointer> {
+    path: Path<'a,K,V,S,P>,
+    index: usize
+}
+
+/// An entry for a key that is not in the map
+pub struct VacantEntry<'a,K,V,S = RandomState,P: PointerKind = RcPointer> {
+    path: Path<'a,K,V,S,P>,
+    key: K
+}
+
+/// the nodes from the root of a map down to the one with the slot for `hash` that holds no child
+/// node, that is the slot where a key with that hash is or would go
+struct Path<'a,K,V,S,P: PointerKind> {
+    map: &'a HashMap<K,V,S,P>,
+    nodes: Vec<&'a Node<K,V,P>>,
+    hash: u64
+}
+
+impl<'a,K,V,S,P: PointerKind> Path<'a,K,V,S,P> {
+    fn new(map: &'a HashMap<K,V,S,P>, hash: u64) -> Path<'a,K,V,S,P> {
+        let mut nodes = Vec::with_capacity(MAX_DEPTH as usize);
+        let mut node: &'a Node<K,V,P> = &map.map;
+        loop {
+            nodes.push(node);
+            match node.slot(1 << split_hash(hash, nodes.len() as u32 - 1)).branch() {
+                Some(child) => node = child,
+                None => return Path { map, nodes, hash }
+            }
+        }
+    }
+
+    fn depth(&self) -> u32 { self.nodes.len() as u32 - 1 }
+
+    /// Return what the last node on the path holds in the slot for `hash`
+    fn slot(&self) -> SlotRef<'a,K,V,P> {
+        self.nodes[self.nodes.len() - 1].slot(1 << split_hash(self.hash, self.depth()))
+    }
+
+    /// Return the buckets in the slot at the end of the path, when `hash` is the hash of a key
+    /// that is there
+    fn buckets(&self) -> &'a [Bucket<K,V>] {
+        self.slot().single_hash().unwrap().1
+    }
+
+    /// Return the index of the bucket for `key` in the slot at the end of the path, if it is
+    /// there
+    fn position<Q>(&self, key: &Q) -> Option<usize> where K: Borrow<Q>, Q: Eq + ?Sized {
+        match self.slot().single_hash() {
+            Some((h, buckets)) if h == self.hash => {
+                buckets.iter().position(|b| key.eq(b.key.borrow()))
+            }
+            _ => None
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, S, P: PointerKind> Path<'_,K,V,S,P> {
+    /// Return a new version of the map, with `slot` in place of the slot at the end of the path.
+    /// Each node on the path is copied with its new child and then made into what its parent's
+    /// slot should hold, as in `Node::merge`, so that removing a key keeps the shape canonical.
+    fn rebuild(&self, mut slot: Option<Slot<K,V,P>>) -> HashMap<K,V,S,P> {
+        for (depth, node) in self.nodes.iter().enumerate().rev() {
+            let bit = 1 << split_hash(self.hash, depth as u32);
+            let mut node = (*node).clone();
+            if (node.datamap | node.nodemap) & bit != 0 {
+                node.take(bit);
+            }
+            if let Some(slot) = slot {
+                node.put(bit, slot);
+            }
+            if depth == 0 {
+                return self.map.with_root(node);
+            }
+            slot = node.into_slot();
+        }
+        unreachable!()
+    }
+}
+
+impl<'a,K,V,S,P: PointerKind> Entry<'a,K,V,S,P> {
+    /// Return the key of the entry
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key()
+        }
+    }
+}
+
+impl<'a,K: Clone,V: Clone,S,P: PointerKind> Entry<'a,K,V,S,P> {
+    /// Return the map with `default` inserted if the key is not there yet, or else the map as it is
+    pub fn or_insert(self, default: V) -> HashMap<K,V,S,P> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Return the map with the value `f()` inserted if the key is not there yet, or else the map
+    /// as it is
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> HashMap<K,V,S,P> {
+        match self {
+            Entry::Occupied(entry) => entry.path.map.clone(),
+            Entry::Vacant(entry) => entry.insert(f())
+        }
+    }
+
+    /// Return the map with the value of the key changed by `f` if the key is there, or else the
+    /// map as it is
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> HashMap<K,V,S,P> {
+        match self {
+            Entry::Occupied(entry) => {
+                let mut value = entry.get().clone();
+                f(&mut value);
+                entry.insert(value)
+            }
+            Entry::Vacant(entry) => entry.path.map.clone()
+        }
+    }
+}
+
+impl<'a,K,V,S,P: PointerKind> OccupiedEntry<'a,K,V,S,P> {
+    /// Return the key as it is stored in the map
+    pub fn key(&self) -> &'a K { &self.path.buckets()[self.index].key }
+
+    /// Return the value of the key
+    pub fn get(&self) -> &'a V { &self.path.buckets()[self.index].value }
+}
+
+impl<'a,K: Clone,V: Clone,S,P: PointerKind> OccupiedEntry<'a,K,V,S,P> {
+    /// Return a new map in which the key has the value `value`
+    pub fn insert(self, value: V) -> HashMap<K,V,S,P> {
+        let mut buckets = self.path.buckets().to_vec();
+        buckets[self.index].value = value;
+        self.path.rebuild(Slot::from_buckets(self.path.hash, buckets))
+    }
+
+    /// Return a new map without the key
+    pub fn remove(self) -> HashMap<K,V,S,P> {
+        let mut buckets = self.path.buckets().to_vec();
+        buckets.swap_remove(self.index);
+        self.path.rebuild(Slot::from_buckets(self.path.hash, buckets))
+    }
+
+    /// Return a new map where the value is changed to `f(value)`, or removed if that is `None`.
+    /// If the value stays the same, the map is returned as it is.
+    fn update<F>(self, f: F) -> HashMap<K,V,S,P> where V: PartialEq, F: FnOnce(&V) -> Option<V> {
+        match f(self.get()) {
+            Some(ref v) if v == self.get() => self.path.map.clone(),
+            Some(v) => self.insert(v),
+            None => self.remove()
+        }
+    }
+}
+
+impl<'a,K,V,S,P: PointerKind> VacantEntry<'a,K,V,S,P> {
+    /// Return the key that would be inserted
+    pub fn key(&self) -> &K { &self.key }
+
+    /// Take back the key
+    pub fn into_key(self) -> K { self.key }
+}
+
+impl<'a,K: Clone,V: Clone,S,P: PointerKind> VacantEntry<'a,K,V,S,P> {
+    /// Return a new map in which the key has the value `value`
+    pub fn insert(self, value: V) -> HashMap<K,V,S,P> {
+        let hash = self.path.hash;
+        let bucket = Bucket { hash, key: self.key, value };
+        let slot = self.path.slot();
+        let slot = match slot.single_hash() {
+            None => Some(Data(bucket)),
+            Some((h, buckets)) if h == hash => {
+                let mut buckets = buckets.to_vec();
+                buckets.push(bucket);
+                Slot::from_buckets(hash, buckets)
+            }
+            Some((h, _)) => {
+                let node = Node::join(slot.to_slot().unwrap(), h, Data(bucket), hash,
+                                      self.path.depth() + 1);
+                Some(Child(P::new(Branches(node))))
+            }
+        };
+        self.path.rebuild(slot)
+    }
+}
+
+
+
+/// Iterator over the entries of a `HashMap`. It walks the trie with an explicit stack of the
+/// children left to visit at each level, which is allocated once with room for `MAX_DEPTH`.
+pub struct Iter<'a,K,V,P: PointerKind> {
+    nodes: Vec<slice::Iter<'a, Link<K,V,P>>>,
+    data: slice::Iter<'a, Bucket<K,V>>,
+    remaining: usize
+}
+
+impl<'a,K,V,P: PointerKind> Iter<'a,K,V,P> {
+    /// An iterator over the entries in one slot of a node
+    fn slot(slot: SlotRef<'a,K,V,P>) -> Iter<'a,K,V,P> {
+        match slot {
+            SlotRef::Empty => Iter { nodes: Vec::new(), data: [].iter(), remaining: 0 },
+            SlotRef::Data(b) => {
+                Iter { nodes: Vec::new(), data: slice::from_ref(b).iter(), remaining: 1 }
+            }
+            SlotRef::Child(child) => {
+                let nodes = vec![slice::from_ref(child).iter()];
+                Iter { nodes, data: [].iter(), remaining: child.len() }
+            }
+        }
+    }
+}
+
+impl<'a, K, V, P: PointerKind> Iterator for Iter<'a,K,V,P> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            if let Some(b) = self.data.next() {
+                self.remaining -= 1;
+                return Some((&b.key, &b.value));
+            }
+            let child = loop {
+                match self.nodes.last_mut()?.next() {
+                    Some(child) => break child,
+                    None => { self.nodes.pop(); }
+                }
+            };
+            match **child {
+                Buckets(ref leaf) => self.data = leaf.buckets.iter(),
+                Branches(ref node) => {
+                    self.data = node.data.iter();
+                    self.nodes.push(node.nodes.iter());
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, P: PointerKind> ExactSizeIterator for Iter<'_,K,V,P> {}
+
+impl<K, V, P: PointerKind> FusedIterator for Iter<'_,K,V,P> {}
+
+impl<K, V, P: PointerKind> Clone for Iter<'_,K,V,P> {
+    fn clone(&self) -> Self {
+        Iter { nodes: self.nodes.clone(), data: self.data.clone(), remaining: self.remaining }
+    }
+}
+
+/// Iterator over the keys of a `HashMap`
+pub struct Keys<'a,K,V,P: PointerKind> {
+    iter: Iter<'a,K,V,P>
+}
 
-use std::container::{Container, Map, Set};
-use std::cmp::{Eq, Equiv};
-use std::hash::Hash;
-use std::option::{None, Option, Some};
-use std::{u8, uint};
+impl<'a, K, V, P: PointerKind> Iterator for Keys<'a,K,V,P> {
+    type Item = &'a K;
 
-#[cfg(target_word_size = "64")]
-static LOG_UINT_SIZE: u64 = 6;
+    fn next(&mut self) -> Option<&'a K> { self.iter.next().map(|(k, _)| k) }
 
-#[cfg(target_word_size = "32")]
-static LOG_UINT_SIZE: u64 = 5;
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
 
-#[cfg(target_word_size = "16")]
-static LOG_UINT_SIZE: u64 = 4;
+impl<K, V, P: PointerKind> ExactSizeIterator for Keys<'_,K,V,P> {}
 
-#[cfg(target_word_size = "8")]
-static LOG_UINT_SIZE: u64 = 3;
+impl<K, V, P: PointerKind> FusedIterator for Keys<'_,K,V,P> {}
 
-/// returns index to bitset and remaining hash for next round
-fn split_hash(h: u64) -> (uint, u64) {
-    (h as uint & ((uint::max_value - 1) >> 1), h >>  LOG_UINT_SIZE)
+impl<K, V, P: PointerKind> Clone for Keys<'_,K,V,P> {
+    fn clone(&self) -> Self { Keys { iter: self.iter.clone() } }
 }
 
-struct Bucket<K,V> {
-    key: K,
-    value: V
+/// Iterator over the values of a `HashMap`
+pub struct Values<'a,K,V,P: PointerKind> {
+    iter: Iter<'a,K,V,P>
 }
 
-// Arrays are deceptively fixed size to avoid boxing. Always allocate memory and then cast to HAMT<T> !
+impl<'a, K, V, P: PointerKind> Iterator for Values<'a,K,V,P> {
+    type Item = &'a V;
 
-enum HAMT<K,V> {
-    Buckets (u64, [Bucket<K,V>, ..uint::max_value] ),
-    Branches (uint, [@HAMT<K,V>, ..uint::bits] )
+    fn next(&mut self) -> Option<&'a V> { self.iter.next().map(|(_, v)| v) }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
 }
 
-impl<K: Hash + Eq, V: Clone> HAMT<K,V> {
-    fn find(@self, key: K, hash: u64)
-            -> Option<V> {
+impl<K, V, P: PointerKind> ExactSizeIterator for Values<'_,K,V,P> {}
 
-        let mut current: @HAMT<K,V> = self;
-        let mut partial: u64 = hash;
+impl<K, V, P: PointerKind> FusedIterator for Values<'_,K,V,P> {}
 
-        loop {
-            match *current {
-                Buckets (h, ref buckets) => return match h == hash {
-                    true => buckets.iter().find(|b| key.eq(&b.key)).map(|b| b.value.clone()),
-                    false => None
-                },
-                Branches (bitset, ref branches) => match bitset & (1u << partial) {
-                    0 => return None,
-                    _ => {
-                        let (index, p2) = split_hash(partial);
-                        partial = p2;
-                        current = branches[index];
+impl<K, V, P: PointerKind> Clone for Values<'_,K,V,P> {
+    fn clone(&self) -> Self { Values { iter: self.iter.clone() } }
+}
+
+/// One change between two versions of a `HashMap`, as returned by `HashMap::diff`
+#[derive(Debug, PartialEq, Eq)]
+pub enum DiffItem<'a,K,V> {
+    /// The key is only in the new map
+    Added (&'a K, &'a V),
+    /// The key is only in the old map
+    Removed (&'a K, &'a V),
+    /// The key is in both maps, with the old and then the new value
+    Changed (&'a K, &'a V, &'a V)
+}
+
+impl<K,V> Clone for DiffItem<'_,K,V> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<K,V> Copy for DiffItem<'_,K,V> {}
+
+/// what one of the tries being compared by a `Diff` has at some place in the trie: either a node,
+/// or a slot whose keys all have one hash, which stands for a node with only that slot in it
+enum Side<'a,K,V,P: PointerKind> {
+    Branch (&'a Node<K,V,P>),
+    Lone (SlotRef<'a,K,V,P>)
+}
+
+impl<K,V,P: PointerKind> Clone for Side<'_,K,V,P> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<K,V,P: PointerKind> Copy for Side<'_,K,V,P> {}
+
+impl<'a,K,V,P: PointerKind> Side<'a,K,V,P> {
+    fn new(slot: SlotRef<'a,K,V,P>) -> Side<'a,K,V,P> {
+        match slot.branch() {
+            Some(node) => Side::Branch(node),
+            None => Side::Lone(slot)
+        }
+    }
+
+    /// Return the bitset of the slots that are present, for a node at `depth`
+    fn bits(self, depth: u32) -> Bitmap {
+        match self {
+            Side::Branch(node) => node.datamap | node.nodemap,
+            Side::Lone(slot) => match slot.single_hash() {
+                Some((hash, _)) => 1 << split_hash(hash, depth),
+                None => 0
+            }
+        }
+    }
+
+    fn slot(self, bit: Bitmap, depth: u32) -> SlotRef<'a,K,V,P> {
+        match self {
+            Side::Branch(node) => node.slot(bit),
+            Side::Lone(slot) if self.bits(depth) == bit => slot,
+            Side::Lone(_) => SlotRef::Empty
+        }
+    }
+}
+
+/// a place at `depth` in the tries being compared by a `Diff`, with the slots left to look at
+struct Frame<'a,K,V,P: PointerKind> {
+    a: Side<'a,K,V,P>,
+    b: Side<'a,K,V,P>,
+    bits: Bitmap,
+    depth: u32
+}
+
+/// Iterator over the changes between two versions of a `HashMap`. It keeps a stack of the places
+/// where the two tries differ that are left to look at, and skips the children they share.
+///
+/// Maps with different hashers can't be compared that way, so for those it goes through all the
+/// entries of each and looks them up in the other.
+pub struct Diff<'a,K,V,S,P: PointerKind> {
+    stack: Vec<Frame<'a,K,V,P>>,
+    removed: Iter<'a,K,V,P>,
+    added: Iter<'a,K,V,P>,
+    changed: Vec<DiffItem<'a,K,V>>,
+    old: &'a HashMap<K,V,S,P>,
+    new: &'a HashMap<K,V,S,P>,
+    lookup: bool
+}
+
+impl<'a,K: Eq,V: PartialEq,S,P: PointerKind> Diff<'a,K,V,S,P> {
+    fn push(&mut self, a: Side<'a,K,V,P>, b: Side<'a,K,V,P>, depth: u32) {
+        self.stack.push(Frame { a, b, bits: a.bits(depth) | b.bits(depth), depth });
+    }
+
+    /// Compare what the two tries hold in one slot of a node at `depth`, either by reporting the
+    /// changes straight away or by pushing it onto the stack to look inside
+    fn compare(&mut self, a: SlotRef<'a,K,V,P>, b: SlotRef<'a,K,V,P>, depth: u32) {
+        match (a, b) {
+            (_, SlotRef::Empty) => self.removed = Iter::slot(a),
+            (SlotRef::Empty, _) => self.added = Iter::slot(b),
+            (SlotRef::Child(x), SlotRef::Child(y)) if P::ptr_eq(x, y) => (),
+            _ => match (a.single_hash(), b.single_hash()) {
+                (Some((hash, xs)), Some((b_hash, ys))) if hash == b_hash => {
+                    for x in xs {
+                        match ys.iter().find(|y| x.key == y.key) {
+                            Some(y) if x.value != y.value => {
+                                self.changed.push(DiffItem::Changed(&x.key, &x.value, &y.value))
+                            }
+                            Some(_) => (),
+                            None => self.changed.push(DiffItem::Removed(&x.key, &x.value))
+                        }
+                    }
+                    for y in ys.iter().filter(|y| !xs.iter().any(|x| x.key == y.key)) {
+                        self.changed.push(DiffItem::Added(&y.key, &y.value));
                     }
                 }
+                _ => self.push(Side::new(a), Side::new(b), depth + 1)
+            }
+        }
+    }
+}
+
+impl<'a,K: Hash + Eq,V: PartialEq,S: BuildHasher,P: PointerKind> Iterator for Diff<'a,K,V,S,P> {
+    type Item = DiffItem<'a,K,V>;
+
+    fn next(&mut self) -> Option<DiffItem<'a,K,V>> {
+        loop {
+            if let Some((k, v)) = self.removed.next() {
+                let found = if self.lookup { self.new.get(k) } else { None };
+                match found {
+                    Some(w) if w == v => continue,
+                    Some(w) => return Some(DiffItem::Changed(k, v, w)),
+                    None => return Some(DiffItem::Removed(k, v))
+                }
+            }
+            if let Some((k, v)) = self.added.next() {
+                match self.lookup && self.old.contains_key(k) {
+                    true => continue,
+                    false => return Some(DiffItem::Added(k, v))
+                }
             }
+            if let Some(item) = self.changed.pop() {
+                return Some(item);
+            }
+            let frame = self.stack.last_mut()?;
+            if frame.bits == 0 {
+                self.stack.pop();
+                continue;
+            }
+            let bit = frame.bits & frame.bits.wrapping_neg();
+            frame.bits &= frame.bits - 1;
+            let (a, b, depth) = (frame.a, frame.b, frame.depth);
+            self.compare(a.slot(bit, depth), b.slot(bit, depth), depth);
         }
     }
 }
 
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher, P: PointerKind> FusedIterator
+    for Diff<'_,K,V,S,P> {}
+
+
 
+/// A map that is changed in place, for building up or editing a `HashMap` in a batch.
+///
+/// A node is edited in place when the transient is its only owner, and copied first when it is
+/// still shared with some other version of the map. Nodes are only ever copied once this way, so
+/// a batch of changes costs about as much as making them to a mutable hash table.
+pub struct TransientHashMap<K,V,S = RandomState,P: PointerKind = RcPointer> {
+    size: usize,
+    map: P::Pointer<Node<K,V,P>>,
+    hasher: P::Pointer<S>
+}
 
-#[allow(missing_doc)]
-pub struct HashMap<K,V> {
-    priv size: uint,
-    priv map: HAMT<K,V>
+impl<K,V> TransientHashMap<K,V> {
+    /// Create an empty transient map
+    pub fn new() -> TransientHashMap<K,V> {
+        TransientHashMap::default()
+    }
 }
 
-impl<K:Hash + Eq,V> Container for HashMap<K, V> {
+impl<K,V,S,P: PointerKind> TransientHashMap<K,V,S,P> {
     /// Return the number of elements in the map
-    fn len(&self) -> uint { self.size }
+    pub fn len(&self) -> usize { self.size }
+
+    /// Return true if the map contains no elements
+    pub fn is_empty(&self) -> bool { self.size == 0 }
+
+    /// Freeze the transient into a persistent map, without copying anything
+    pub fn persistent(self) -> HashMap<K,V,S,P> {
+        HashMap { size: self.size, map: self.map, hasher: self.hasher }
+    }
 }
 
-impl<K:Hash + Eq,V> Map<K, V> for HashMap<K, V> {
+impl<K: Hash + Eq, V, S: BuildHasher, P: PointerKind> TransientHashMap<K,V,S,P> {
     /// Return a reference to the value corresponding to the key
-    fn find<'a>(&'a self, k: &K) -> Option<&'a V> {
-        match self.bucket_for_key(k) {
-            FoundEntry(idx) => Some(self.value_for_bucket(idx)),
-            TableFull | FoundHole(_) => None,
+    pub fn get<Q>(&self, k: &Q) -> Option<&V> where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.find(k, self.hasher.hash_one(k), 0).map(|b| &b.value)
+    }
+
+    /// Return true if the map contains a value for the key
+    pub fn contains_key<Q>(&self, k: &Q) -> bool where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.find(k, self.hasher.hash_one(k), 0).is_some()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, S: BuildHasher, P: PointerKind> TransientHashMap<K,V,S,P> {
+    /// Map `k` to `v`, returning the value `k` had before if there was one
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let bucket = Bucket { hash: self.hasher.hash_one(&k), key: k, value: v };
+        let replaced = P::make_mut(&mut self.map).insert(bucket, 0);
+        if replaced.is_none() {
+            self.size += 1;
+        }
+        replaced
+    }
+
+    /// Remove `k`, returning its value if it was in the map
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V> where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let h = self.hasher.hash_one(k);
+        // look first, so that nothing is copied when there is nothing to remove
+        self.map.find(k, h, 0)?;
+        self.size -= 1;
+        Some(P::make_mut(&mut self.map).remove(k, h, 0).value)
+    }
+}
+
+impl<K,V,S: Default,P: PointerKind> Default for TransientHashMap<K,V,S,P> {
+    fn default() -> TransientHashMap<K,V,S,P> { HashMap::default().transient() }
+}
+
+impl<K,V,S,P: PointerKind> Extend<(K,V)> for TransientHashMap<K,V,S,P>
+        where K: Hash + Eq + Clone, V: Clone, S: BuildHasher {
+    fn extend<I: IntoIterator<Item = (K,V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+
+
+/// One change to one key of a `HashMap`, as recorded in a `Patch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Edit<K,V> {
+    /// Add a key that is not in the map
+    Insert { key: K, value: V },
+    /// Remove a key that maps to `old`
+    Remove { key: K, old: V },
+    /// Change the value of a key from `old` to `new`
+    Replace { key: K, old: V, new: V }
+}
+
+/// The error for a key where a patch doesn't fit the map it is applied to, or the patch it is
+/// composed with. The patch expected `key` to have the value `expected`, but it had `found`;
+/// `None` means that the key is not in the map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Conflict<K,V> {
+    /// The key whose value has diverged
+    pub key: K,
+    /// The value the patch expected the key to have
+    pub expected: Option<V>,
+    /// The value the key had instead
+    pub found: Option<V>
+}
+
+impl<K: Debug, V: Debug> Display for Conflict<K,V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conflict at key {:?}: expected {:?}, found {:?}", self.key, self.expected,
+               self.found)
+    }
+}
+
+impl<K: Debug, V: Debug> Error for Conflict<K,V> {}
+
+/// The error of `Patch::then` and `Patch::apply`, with a `Conflict` for each key that doesn't fit
+pub type Conflicts<K,V> = Vec<Conflict<K,V>>;
+
+/// A set of changes to the entries of a `HashMap`, at most one for each key. A patch can be
+/// computed from two versions of a map, composed with the patch that follows it, inverted, and
+/// applied to any map with the same keys, such as a copy of the old version on another replica.
+///
+/// `to_edits` and `from_edits` convert a patch to and from a list of `Edit`s, each for a
+/// different key. With the `serde` feature, a patch is serialized as that list. The edits are
+/// listed in the order of the trie, so a patch whose hasher is `FixedState` always encodes to
+/// the same bytes; with a random hasher the order differs between runs. Deserializing takes the
+/// edits in any order.
+pub struct Patch<K,V,S = RandomState,P: PointerKind = RcPointer> {
+    changes: HashMap<K, Change<V>, S, P>
+}
+
+/// the value a key has before and after a patch, where `None` means that it is not in the map
+#[derive(Clone, PartialEq)]
+struct Change<V> {
+    old: Option<V>,
+    new: Option<V>
+}
+
+impl<V> Change<V> {
+    fn as_edit<'a, K>(&'a self, key: &'a K) -> Edit<&'a K, &'a V> {
+        match (&self.old, &self.new) {
+            (None, Some(value)) => Edit::Insert { key, value },
+            (Some(old), None) => Edit::Remove { key, old },
+            (Some(old), Some(new)) => Edit::Replace { key, old, new },
+            (None, None) => unreachable!()
+        }
+    }
+}
+
+impl<V: Clone> Change<V> {
+    fn to_edit<K: Clone>(&self, key: &K) -> Edit<K,V> {
+        match self.as_edit(key) {
+            Edit::Insert { key, value } => Edit::Insert { key: key.clone(), value: value.clone() },
+            Edit::Remove { key, old } => Edit::Remove { key: key.clone(), old: old.clone() },
+            Edit::Replace { key, old, new } => {
+                Edit::Replace { key: key.clone(), old: old.clone(), new: new.clone() }
+            }
+        }
+    }
+}
+
+impl<K,V> Patch<K,V> {
+    /// Create a patch that changes nothing
+    pub fn new() -> Patch<K,V> {
+        Patch::default()
+    }
+}
+
+impl<K,V,S,P: PointerKind> Patch<K,V,S,P> {
+    /// Return the number of keys the patch changes
+    pub fn len(&self) -> usize { self.changes.len() }
+
+    /// Return true if the patch changes nothing
+    pub fn is_empty(&self) -> bool { self.changes.is_empty() }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone + PartialEq, S: BuildHasher, P: PointerKind> Patch<K,V,S,P> {
+    /// Return the patch that turns `old` into `new`. This uses `HashMap::diff`, so it only looks
+    /// at the parts of the two maps that aren't shared. The patch has the hasher of `old`.
+    pub fn diff(old: &HashMap<K,V,S,P>, new: &HashMap<K,V,S,P>) -> Patch<K,V,S,P> {
+        let mut changes = old.with_root(Node::empty()).transient();
+        for item in old.diff(new) {
+            let (key, change) = match item {
+                DiffItem::Added(k, v) => (k, Change { old: None, new: Some(v.clone()) }),
+                DiffItem::Removed(k, v) => (k, Change { old: Some(v.clone()), new: None }),
+                DiffItem::Changed(k, x, y) => {
+                    (k, Change { old: Some(x.clone()), new: Some(y.clone()) })
+                }
+            };
+            changes.insert(key.clone(), change);
+        }
+        Patch { changes: changes.persistent() }
+    }
+
+    /// Build a patch from a list of edits, which are composed in order. Returns a `Conflict` if
+    /// an edit expects a different value than the edits before it left for its key.
+    pub fn from_edits<I>(edits: I) -> Result<Patch<K,V,S,P>, Conflict<K,V>>
+            where S: Default, I: IntoIterator<Item = Edit<K,V>> {
+        let mut changes: TransientHashMap<K, Change<V>, S, P> = TransientHashMap::default();
+        for edit in edits {
+            let (key, mut change) = match edit {
+                Edit::Insert { key, value } => (key, Change { old: None, new: Some(value) }),
+                Edit::Remove { key, old } => (key, Change { old: Some(old), new: None }),
+                Edit::Replace { key, old, new } => (key, Change { old: Some(old), new: Some(new) })
+            };
+            if let Some(before) = changes.remove(&key) {
+                if before.new != change.old {
+                    return Err(Conflict { key, expected: change.old, found: before.new });
+                }
+                change.old = before.old;
+            }
+            if change.old != change.new {
+                changes.insert(key, change);
+            }
+        }
+        Ok(Patch { changes: changes.persistent() })
+    }
+
+    /// Return the edits that make up the patch, one for each key it changes
+    pub fn to_edits(&self) -> Vec<Edit<K,V>> {
+        self.changes.iter().map(|(k, change)| change.to_edit(k)).collect()
+    }
+
+    /// Return the patch that undoes this one
+    pub fn invert(&self) -> Patch<K,V,S,P> {
+        let swap = |c: &Change<V>| Change { old: c.new.clone(), new: c.old.clone() };
+        Patch { changes: self.changes.map_values(swap) }
+    }
+
+    /// Return the patch that makes the changes of `self` and then those of `next`. Returns a
+    /// `Conflict` for each key that `next` expects to have some other value than `self` leaves it
+    /// with, so every replica gets the same conflicts, though they are listed in the order of the
+    /// trie.
+    pub fn then(&self, next: &Patch<K,V,S,P>) -> Result<Patch<K,V,S,P>, Conflicts<K,V>> {
+        let mut conflicts = Vec::new();
+        let both = |k: &K, a: &Change<V>, b: &Change<V>| {
+            if a.new != b.old {
+                conflicts.push(Conflict { key: k.clone(), expected: b.old.clone(),
+                                          found: a.new.clone() });
+            }
+            let change = Change { old: a.old.clone(), new: b.new.clone() };
+            if change.old != change.new { Some(change) } else { None }
+        };
+        let changes = self.changes.merge(&next.changes, |_, a| Some(a.clone()), both,
+                                         |_, b| Some(b.clone()));
+        if conflicts.is_empty() { Ok(Patch { changes }) } else { Err(conflicts) }
+    }
+
+    /// Apply the patch to `map`, after checking that each key it changes still has the value
+    /// the patch expects. Returns a `Conflict` for each key that has diverged, listed in the
+    /// order of the trie like with `then`.
+    pub fn apply(&self, map: &HashMap<K,V,S,P>) -> Result<HashMap<K,V,S,P>, Conflicts<K,V>> {
+        let mut conflicts = Vec::new();
+        for (k, change) in &self.changes {
+            let found = map.get(k);
+            if found != change.old.as_ref() {
+                conflicts.push(Conflict { key: k.clone(), expected: change.old.clone(),
+                                          found: found.cloned() });
+            }
+        }
+        if conflicts.is_empty() { Ok(self.force_apply(map)) } else { Err(conflicts) }
+    }
+
+    /// Apply the patch to `map` without checking the old values, so that each key the patch
+    /// changes ends up with the new value whatever it had before
+    pub fn force_apply(&self, map: &HashMap<K,V,S,P>) -> HashMap<K,V,S,P> {
+        let mut map = map.transient();
+        for (k, change) in &self.changes {
+            match change.new {
+                Some(ref v) => { map.insert(k.clone(), v.clone()); }
+                None => { map.remove(k); }
+            }
         }
+        map.persistent()
+    }
+}
+
+impl<K,V,S,P: PointerKind> Clone for Patch<K,V,S,P> {
+    fn clone(&self) -> Patch<K,V,S,P> {
+        Patch { changes: self.changes.clone() }
+    }
+}
+
+impl<K: Debug, V: Debug, S, P: PointerKind> Debug for Patch<K,V,S,P> {
+    /// Format the patch as the list of its edits, in the order of `to_edits`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.changes.iter().map(|(k, change)| change.as_edit(k))).finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K,V,S,P> serde::Serialize for Patch<K,V,S,P>
+        where K: serde::Serialize, V: serde::Serialize, P: PointerKind {
+    fn serialize<Z: serde::Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        serializer.collect_seq(self.changes.iter().map(|(k, change)| change.as_edit(k)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S, P> serde::Deserialize<'de> for Patch<K,V,S,P>
+        where K: serde::Deserialize<'de> + Hash + Eq + Clone,
+              V: serde::Deserialize<'de> + Clone + PartialEq,
+              S: BuildHasher + Default, P: PointerKind {
+    /// Read a list of edits and compose them with `from_edits`, failing if they conflict
+    fn deserialize<D>(deserializer: D) -> Result<Patch<K,V,S,P>, D::Error>
+            where D: serde::Deserializer<'de> {
+        let edits = Vec::<Edit<K,V>>::deserialize(deserializer)?;
+        Patch::from_edits(edits).map_err(|_| {
+            serde::de::Error::custom("the patch has edits for one key that don't follow each other")
+        })
+    }
+}
+
+impl<K,V,S: Default,P: PointerKind> Default for Patch<K,V,S,P> {
+    fn default() -> Patch<K,V,S,P> {
+        Patch { changes: HashMap::default() }
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq, S: BuildHasher, P: PointerKind> PartialEq for Patch<K,V,S,P> {
+    fn eq(&self, other: &Patch<K,V,S,P>) -> bool {
+        self.changes == other.changes
     }
 }
 
+impl<K: Hash + Eq, V: Eq, S: BuildHasher, P: PointerKind> Eq for Patch<K,V,S,P> {}
+
+
+
+/// A persistent set of values of type `T`, hashing them with `S`. Like `HashMap`, every set
+/// derived from another one keeps its hasher.
+pub struct HashSet<T,S = RandomState,P: PointerKind = RcPointer> {
+    map: HashMap<T, (), S, P>
+}
+
+/// A `HashSet` whose versions can be sent to and shared between threads
+pub type SyncHashSet<T,S = RandomState> = HashSet<T,S,ArcPointer>;
+
+impl<T> HashSet<T> {
+    /// Create an empty set, with new random keys for its hasher
+    pub fn new() -> HashSet<T> {
+        HashSet::default()
+    }
+}
 
+impl<T,S> HashSet<T,S> {
+    /// Create an empty set that hashes its values with `hasher`
+    pub fn with_hasher(hasher: S) -> HashSet<T,S> {
+        HashSet { map: HashMap::with_hasher(hasher) }
+    }
+}
 
-#[allow(missing_doc)]
-pub struct HashSet<T> {
-    priv map: HashMap<T, ()>
+impl<T> SyncHashSet<T> {
+    /// Create an empty set that can be shared between threads
+    pub fn new_sync() -> SyncHashSet<T> {
+        HashSet::default()
+    }
 }
 
-impl<T:Hash + Eq> Eq for HashSet<T> {
-    fn eq(&self, other: &HashSet<T>) -> bool { self.map == other.map }
-    fn ne(&self, other: &HashSet<T>) -> bool { self.map != other.map }
+impl<T,S> SyncHashSet<T,S> {
+    /// Create an empty set that can be shared between threads and hashes its values with `hasher`
+    pub fn with_hasher_sync(hasher: S) -> SyncHashSet<T,S> {
+        HashSet { map: HashMap::with_hasher_sync(hasher) }
+    }
 }
 
-impl<T:Hash + Eq> Container for HashSet<T> {
+impl<T,S,P: PointerKind> HashSet<T,S,P> {
     /// Return the number of elements in the set
-    fn len(&self) -> uint { self.map.len() }
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Return a reference to the set's hasher
+    pub fn hasher(&self) -> &S { self.map.hasher() }
+
+    /// Return the family of the set's hasher, to create other maps and sets that share it
+    pub fn family(&self) -> HasherFamily<S,P> { self.map.family() }
+
+    /// Return true if the set contains no elements
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// An iterator visiting all values, in the order of the trie
+    pub fn iter(&self) -> SetIter<'_,T,P> {
+        SetIter { iter: self.map.keys() }
+    }
 }
 
-impl<T:Hash + Eq> Set<T> for HashSet<T> {
-    /// Return true if the set contains a value
-    fn contains(&self, value: &T) -> bool { self.map.contains_key(value) }
+impl<T: Hash + Eq, S: BuildHasher, P: PointerKind> HashSet<T,S,P> {
+    /// Return true if the set contains a value, which may be any borrowed form of `T`
+    pub fn contains<Q>(&self, value: &Q) -> bool where T: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.contains_key(value)
+    }
 
     /// Return true if the set has no elements in common with `other`.
     /// This is equivalent to checking for an empty intersection.
-    fn is_disjoint(&self, other: &HashSet<T>) -> bool {
-        self.iter().all(|v| !other.contains(v))
+    pub fn is_disjoint(&self, other: &HashSet<T,S,P>) -> bool {
+        self.map.keys_disjoint(&other.map)
     }
 
-    /// Return true if the set is a subset of another
-    fn is_subset(&self, other: &HashSet<T>) -> bool {
-        self.iter().all(|v| other.contains(v))
+    /// Return true if the set is a subset of another. Like `is_disjoint` and `==`, this walks
+    /// both tries together and skips the subtrees they share, so checking a set against an
+    /// earlier or later version of itself only looks at the parts where they differ.
+    pub fn is_subset(&self, other: &HashSet<T,S,P>) -> bool {
+        self.map.keys_subset(&other.map)
     }
 
     /// Return true if the set is a superset of another
-    fn is_superset(&self, other: &HashSet<T>) -> bool {
+    pub fn is_superset(&self, other: &HashSet<T,S,P>) -> bool {
         other.is_subset(self)
     }
 }
+
+impl<T: Hash + Eq + Clone, S: BuildHasher, P: PointerKind> HashSet<T,S,P> {
+    /// Return a new set that also contains `value`
+    pub fn insert(&self, value: T) -> HashSet<T,S,P> {
+        HashSet { map: self.map.insert(value, ()) }
+    }
+
+    /// Return a new set without `value`
+    pub fn remove<Q>(&self, value: &Q) -> HashSet<T,S,P> where T: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        HashSet { map: self.map.remove(value) }
+    }
+
+    /// Return the values that are in `self`, in `other`, or in both.
+    ///
+    /// Like the other set operations, this merges the two tries node by node. A subtree that
+    /// only one side has, or that both sides share, goes into the result without being copied.
+    pub fn union(&self, other: &HashSet<T,S,P>) -> HashSet<T,S,P> {
+        let keep = Keep { left: true, both: true, right: true };
+        HashSet { map: self.map.merge_keys(&other.map, keep) }
+    }
+
+    /// Return the values that are in both `self` and `other`
+    pub fn intersection(&self, other: &HashSet<T,S,P>) -> HashSet<T,S,P> {
+        let keep = Keep { left: false, both: true, right: false };
+        HashSet { map: self.map.merge_keys(&other.map, keep) }
+    }
+
+    /// Return the values that are in `self` but not in `other`
+    pub fn difference(&self, other: &HashSet<T,S,P>) -> HashSet<T,S,P> {
+        let keep = Keep { left: true, both: false, right: false };
+        HashSet { map: self.map.merge_keys(&other.map, keep) }
+    }
+
+    /// Return the values that are in exactly one of `self` and `other`
+    pub fn symmetric_difference(&self, other: &HashSet<T,S,P>) -> HashSet<T,S,P> {
+        let keep = Keep { left: true, both: false, right: true };
+        HashSet { map: self.map.merge_keys(&other.map, keep) }
+    }
+
+    /// Return a map from each value of the set to `f(value)`. The map's trie has the same shape
+    /// and stored hashes as the set's, so it is built in one pass without hashing any value.
+    pub fn to_map<V, F: FnMut(&T) -> V>(&self, mut f: F) -> HashMap<T,V,S,P> {
+        self.map.map_values_with_key(|k, _| f(k))
+    }
+
+    /// Keep only the values for which `f` is true
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.map.retain(|v, _| f(v));
+    }
+
+    /// Split the set into one with the values for which `f` is true and one with the rest, in
+    /// one pass over the trie. See `HashMap::partition`.
+    pub fn partition<F: FnMut(&T) -> bool>(&self, mut f: F) -> (HashSet<T,S,P>, HashSet<T,S,P>) {
+        let (yes, no) = self.map.partition(|v, _| f(v));
+        (HashSet { map: yes }, HashSet { map: no })
+    }
+
+    /// Remove the values for which `f` is true and return them as a new set
+    pub fn split_off_by<F: FnMut(&T) -> bool>(&mut self, mut f: F) -> HashSet<T,S,P> {
+        HashSet { map: self.map.split_off_by(|v, _| f(v)) }
+    }
+}
+
+impl<T,S,P: PointerKind> HashSet<T,S,P> {
+    /// Return a transient version of the set for making many changes in a row. It starts out
+    /// sharing every node with `self`, which is left unchanged.
+    pub fn transient(&self) -> TransientHashSet<T,S,P> {
+        TransientHashSet { map: self.map.transient() }
+    }
+}
+
+impl<T,S,P: PointerKind> Clone for HashSet<T,S,P> {
+    fn clone(&self) -> HashSet<T,S,P> {
+        HashSet { map: self.map.clone() }
+    }
+}
+
+impl<T: Debug, S, P: PointerKind> Debug for HashSet<T,S,P> {
+    /// Format the values in the order of the trie, like `HashMap`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<T,S: Default,P: PointerKind> Default for HashSet<T,S,P> {
+    fn default() -> HashSet<T,S,P> {
+        HashSet { map: HashMap::default() }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher, P: PointerKind> PartialEq for HashSet<T,S,P> {
+    fn eq(&self, other: &HashSet<T,S,P>) -> bool {
+        self.map == other.map
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher, P: PointerKind> Eq for HashSet<T,S,P> {}
+
+impl<'a, T, S, P: PointerKind> IntoIterator for &'a HashSet<T,S,P> {
+    type Item = &'a T;
+    type IntoIter = SetIter<'a,T,P>;
+
+    fn into_iter(self) -> SetIter<'a,T,P> { self.iter() }
+}
+
+impl<T,S,P: PointerKind> FromIterator<T> for HashSet<T,S,P>
+        where T: Hash + Eq + Clone, S: BuildHasher + Default {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> HashSet<T,S,P> {
+        let mut set = TransientHashSet::default();
+        set.extend(iter);
+        set.persistent()
+    }
+}
+
+impl<T: Hash + Eq + Clone, S: BuildHasher, P: PointerKind> Extend<T> for HashSet<T,S,P> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|v| (v, ())));
+    }
+}
+
+
+
+/// Iterator over the values of a `HashSet`
+pub struct SetIter<'a,T,P: PointerKind> {
+    iter: Keys<'a,T,(),P>
+}
+
+impl<'a, T, P: PointerKind> Iterator for SetIter<'a,T,P> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> { self.iter.next() }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+
+impl<T, P: PointerKind> ExactSizeIterator for SetIter<'_,T,P> {}
+
+impl<T, P: PointerKind> FusedIterator for SetIter<'_,T,P> {}
+
+impl<T, P: PointerKind> Clone for SetIter<'_,T,P> {
+    fn clone(&self) -> Self { SetIter { iter: self.iter.clone() } }
+}
+
+
+
+/// A set that is changed in place, for building up or editing a `HashSet` in a batch. See
+/// `TransientHashMap`.
+pub struct TransientHashSet<T,S = RandomState,P: PointerKind = RcPointer> {
+    map: TransientHashMap<T, (), S, P>
+}
+
+impl<T> TransientHashSet<T> {
+    /// Create an empty transient set
+    pub fn new() -> TransientHashSet<T> {
+        TransientHashSet::default()
+    }
+}
+
+impl<T,S,P: PointerKind> TransientHashSet<T,S,P> {
+    /// Return the number of elements in the set
+    pub fn len(&self) -> usize { self.map.len() }
+
+    /// Return true if the set contains no elements
+    pub fn is_empty(&self) -> bool { self.map.is_empty() }
+
+    /// Freeze the transient into a persistent set, without copying anything
+    pub fn persistent(self) -> HashSet<T,S,P> {
+        HashSet { map: self.map.persistent() }
+    }
+}
+
+impl<T: Hash + Eq, S: BuildHasher, P: PointerKind> TransientHashSet<T,S,P> {
+    /// Return true if the set contains a value
+    pub fn contains<Q>(&self, value: &Q) -> bool where T: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.contains_key(value)
+    }
+}
+
+impl<T: Hash + Eq + Clone, S: BuildHasher, P: PointerKind> TransientHashSet<T,S,P> {
+    /// Add a value to the set, returning true if it was not already there
+    pub fn insert(&mut self, value: T) -> bool {
+        self.map.insert(value, ()).is_none()
+    }
+
+    /// Remove a value from the set, returning true if it was there
+    pub fn remove<Q>(&mut self, value: &Q) -> bool where T: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.map.remove(value).is_some()
+    }
+}
+
+impl<T,S: Default,P: PointerKind> Default for TransientHashSet<T,S,P> {
+    fn default() -> TransientHashSet<T,S,P> {
+        TransientHashSet { map: TransientHashMap::default() }
+    }
+}
+
+impl<T,S,P: PointerKind> Extend<T> for TransientHashSet<T,S,P>
+        where T: Hash + Eq + Clone, S: BuildHasher {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|v| (v, ())));
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests;
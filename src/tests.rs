@@ -0,0 +1,1033 @@
+use super::*;
+
+use std::collections::HashMap as StdMap;
+use std::fmt::Debug;
+
+/// A key whose hash is picked by the test, so that keys can be made to share any prefix of their
+/// hash, or all of it. `id` tells apart keys that have the same hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    hash: u64,
+    id: u32
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) { state.write_u64(self.hash) }
+}
+
+/// Hashes a `Key` to its `hash`, and any other `u64` to itself
+#[derive(Clone, Copy, Default)]
+struct Exact;
+
+struct ExactHasher(u64);
+
+impl BuildHasher for Exact {
+    type Hasher = ExactHasher;
+
+    fn build_hasher(&self) -> ExactHasher { ExactHasher(0) }
+}
+
+impl Hasher for ExactHasher {
+    fn write(&mut self, _: &[u8]) { unreachable!() }
+
+    fn write_u64(&mut self, i: u64) { self.0 = i }
+
+    fn finish(&self) -> u64 { self.0 }
+}
+
+/// xorshift, so that the random tests do the same thing in every run
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// A key out of `n` hashes, with up to `ids` keys for each one. With a small `ids` most keys
+    /// have a hash of their own; the hashes only use the bits in `mask`, so a mask with a few
+    /// high bits makes keys that share a long prefix.
+    fn key(&mut self, n: u64, ids: u64, mask: u64) -> Key {
+        let hash = self.below(n).wrapping_mul(0x9e37_79b9_7f4a_7c15) & mask;
+        Key { hash, id: self.below(ids) as u32 }
+    }
+
+    /// A key out of 60 hashes that only differ in their first and last four bits, so that keys
+    /// share long prefixes and often have the same hash
+    fn small_key(&mut self) -> Key { self.key(60, 4, 0xf000_0000_0000_000f) }
+}
+
+/// Two maps with `n` random keys between them, made by `Rng::small_key`, where each key is in
+/// one map or in both, with values that may differ. They share their hasher, so operations on
+/// both walk the two tries together.
+fn random_pair(rng: &mut Rng, n: u64) -> (HashMap<Key,u32,Exact>, HashMap<Key,u32,Exact>) {
+    let mut a = HashMap::with_hasher(Exact);
+    let mut b = a.clone();
+    for _ in 0..n {
+        let (k, v) = (rng.small_key(), rng.below(10) as u32);
+        match rng.below(3) {
+            0 => a = a.insert(k, v),
+            1 => b = b.insert(k, v),
+            _ => (a, b) = (a.insert(k, v), b.insert(k, v + rng.below(2) as u32))
+        }
+    }
+    (a, b)
+}
+
+/// Check the invariants of the trie under `node` at `depth`, whose keys all have the hash bits
+/// `prefix` below the ones it uses itself. Returns the number of keys.
+fn check_node<K,V,P: PointerKind>(node: &Node<K,V,P>, depth: u32, prefix: u64) -> usize {
+    assert_eq!(node.datamap & node.nodemap, 0);
+    assert_eq!(node.data.len(), node.datamap.count_ones() as usize);
+    assert_eq!(node.nodes.len(), node.nodemap.count_ones() as usize);
+    let below = (1 << (depth * BITS_PER_LEVEL)) - 1;
+    let in_slot = |hash: u64, bit: Bitmap| {
+        assert_eq!(hash & below, prefix);
+        assert_eq!(1 << split_hash(hash, depth), bit);
+    };
+    let mut size = 0;
+    for bit in (0..Bitmap::BITS).map(|i| 1 << i) {
+        if node.datamap & bit != 0 {
+            in_slot(node.data[node.data_index(bit)].hash, bit);
+            size += 1;
+        }
+        if node.nodemap & bit == 0 {
+            continue;
+        }
+        match *node.nodes[node.node_index(bit)] {
+            Buckets(ref leaf) => {
+                in_slot(leaf.hash, bit);
+                assert!(leaf.buckets.len() >= 2);
+                assert!(leaf.buckets.iter().all(|b| b.hash == leaf.hash));
+                size += leaf.buckets.len();
+            }
+            Branches(ref child) => {
+                let index = bit.trailing_zeros() as u64;
+                let prefix = prefix | index << (depth * BITS_PER_LEVEL);
+                size += check_node(child, depth + 1, prefix);
+                // the canonical shape: a child holds two keys or more, and never just a
+                // collision, which would be stored in this node's slot instead
+                assert!(child.size >= 2);
+                let lone_leaf = matches!(child.nodes[..], [ref n] if matches!(**n, Buckets(_)));
+                assert!(!(child.data.is_empty() && lone_leaf));
+            }
+        }
+    }
+    assert_eq!(node.size, size);
+    size
+}
+
+/// Check that `map` has a valid trie in its canonical shape, and that it holds exactly the
+/// entries of `model`
+fn check<K,V,S,P>(map: &HashMap<K,V,S,P>, model: &StdMap<K,V>)
+        where K: Hash + Eq + Debug, V: PartialEq + Debug, S: BuildHasher, P: PointerKind {
+    assert_eq!(check_node(&map.map, 0, 0), map.len());
+    assert_eq!(map.len(), model.len());
+    assert_eq!(map.iter().len(), model.len());
+    for (k, v) in map {
+        assert_eq!(model.get(k), Some(v), "{:?}", k);
+    }
+    for (k, v) in model {
+        assert_eq!(map.get(k), Some(v), "{:?}", k);
+    }
+}
+
+/// Check that every hash stored in the trie is the one the map's hasher gives for its key
+fn check_hashes<K: Hash,V,S: BuildHasher,P: PointerKind>(map: &HashMap<K,V,S,P>) {
+    fn go<K: Hash,V,S: BuildHasher,P: PointerKind>(node: &Node<K,V,P>, hasher: &S) {
+        for b in &node.data {
+            assert_eq!(b.hash, hasher.hash_one(&b.key));
+        }
+        for child in &node.nodes {
+            match **child {
+                Buckets(ref leaf) => assert_eq!(leaf.hash, hasher.hash_one(&leaf.buckets[0].key)),
+                Branches(ref node) => go(node, hasher)
+            }
+        }
+    }
+    go(&map.map, map.hasher());
+}
+
+/// Return true if the two tries have the same nodes, with keys of the same hashes in the same
+/// slots
+fn same_shape<K,V,W,P: PointerKind>(a: &Node<K,V,P>, b: &Node<K,W,P>) -> bool {
+    a.datamap == b.datamap && a.nodemap == b.nodemap
+        && a.data.iter().zip(&b.data).all(|(x, y)| x.hash == y.hash)
+        && a.nodes.iter().zip(&b.nodes).all(|(x, y)| match (&**x, &**y) {
+            (Buckets(x), Buckets(y)) => x.hash == y.hash && x.buckets.len() == y.buckets.len(),
+            (Branches(x), Branches(y)) => same_shape(x, y),
+            _ => false
+        })
+}
+
+#[test]
+fn insert_matches_std() {
+    let mut rng = Rng(1);
+    let mut map = HashMap::new();
+    let mut model = StdMap::new();
+    let mut versions = Vec::new();
+    for i in 0..20000 {
+        let k = rng.below(5000) as u32;
+        let next = map.insert(k, i);
+        let added = model.insert(k, i).is_none();
+        assert_eq!(next.len(), map.len() + added as usize);
+        assert_eq!(next.get(&k), Some(&i));
+        map = next;
+        if i % 2000 == 0 {
+            check(&map, &model);
+            versions.push((map.clone(), model.clone()));
+        }
+    }
+    check(&map, &model);
+    check_hashes(&map);
+    for (map, model) in &versions {
+        check(map, model);
+    }
+}
+
+#[test]
+fn insert_copies_only_the_path() {
+    let map: HashMap<u32, u32> = (0..10000).map(|i| (i, i)).collect();
+    for (k, v) in [(5, 0), (20000, 1)] {
+        let next = map.insert(k, v);
+        assert_eq!(map.get(&k), if k == 5 { Some(&5) } else { None });
+        assert_eq!(next.get(&k), Some(&v));
+        let pairs = map.map.nodes.iter().zip(&next.map.nodes);
+        let shared = pairs.filter(|(a, b)| RcPointer::ptr_eq(a, b)).count();
+        assert_eq!(shared, map.map.nodes.len() - 1);
+    }
+}
+
+#[test]
+fn remove_matches_std() {
+    let mut rng = Rng(3);
+    let full: HashMap<u32, u32> = (0..4000).map(|i| (i, i)).collect();
+    let mut model: StdMap<u32, u32> = (0..4000).map(|i| (i, i)).collect();
+    let mut map = full.clone();
+    for i in 0..20000 {
+        let k = rng.below(6000) as u32;
+        if rng.below(3) == 0 {
+            map = map.insert(k, i);
+            model.insert(k, i);
+            continue;
+        }
+        let next = map.remove(&k);
+        match model.remove(&k) {
+            Some(_) => assert_eq!(next.len(), map.len() - 1),
+            None => assert!(next.ptr_eq(&map))
+        }
+        assert!(!next.contains_key(&k));
+        map = next;
+        if i % 2000 == 0 {
+            check(&map, &model);
+        }
+    }
+    check(&map, &model);
+    // the trie is compacted, so it has the shape of one that never had the removed keys
+    let fresh = map.family().map_from(model.iter().map(|(&k, &v)| (k, v)));
+    assert!(same_shape(&map.map, &fresh.map));
+    check(&full, &(0..4000).map(|i| (i, i)).collect());
+    let keys: Vec<u32> = map.keys().copied().collect();
+    let empty = keys.iter().fold(map, |m, k| m.remove(k));
+    assert!(empty.is_empty() && empty.map.data.is_empty() && empty.map.nodes.is_empty());
+}
+
+/// Insert and remove random keys, checking the map against std's after every few steps
+#[test]
+fn borrowed_lookups() {
+    // a map with `String` keys is looked up with `&str`, without making a `String`
+    let words = ["a", "bb", "ccc", ""];
+    let map: HashMap<String, usize> = words.iter().map(|w| (w.to_string(), w.len())).collect();
+    assert_eq!((map.get("a"), map.get(""), map.get("d")), (Some(&1), Some(&0), None));
+    assert_eq!(map.get_key_value("bb"), Some((&"bb".to_string(), &2)));
+    assert!(map.contains_key("ccc") && !map.contains_key("cc"));
+    let removed = map.remove("bb");
+    assert!(removed.len() == 3 && !removed.contains_key("bb") && map.contains_key("bb"));
+    assert!(map.remove("bbb").ptr_eq(&map));
+    assert_eq!(map.update("ccc", |v| Some(v * 10)).get("ccc"), Some(&30));
+    assert!(!map.update("a", |_| None).contains_key("a") && map.update("d", |_| None).ptr_eq(&map));
+    assert_eq!(map.adjust("", |v| v + 1).get(""), Some(&1));
+    let mut transient = map.transient();
+    assert!(transient.get("a") == Some(&1) && transient.contains_key("bb"));
+    assert!(transient.remove("a") == Some(1) && !transient.contains_key("a"));
+    let set: HashSet<String> = words.iter().map(|w| w.to_string()).collect();
+    assert!(set.contains("bb") && set.contains("") && !set.contains("b"));
+    assert!(!set.remove("bb").contains("bb") && set.remove("b").len() == 4);
+}
+
+fn insert_and_remove(rng: &mut Rng, n: u64, ids: u64, mask: u64) {
+    let mut map = HashMap::with_hasher(Exact);
+    let mut model = StdMap::new();
+    for i in 0..3000 {
+        let k = rng.key(n, ids, mask);
+        if rng.below(3) == 0 {
+            map = map.remove(&k);
+            model.remove(&k);
+        } else {
+            map = map.insert(k, i);
+            model.insert(k, i);
+        }
+        if i % 300 == 0 {
+            check(&map, &model);
+        }
+    }
+    check(&map, &model);
+}
+
+#[test]
+fn hashes_with_shared_prefixes() {
+    let mut rng = Rng(4);
+    // keys whose hashes only differ in the high bits go down to the last level of the trie,
+    // which only has the 4 bits that are left of a 64 bit hash
+    for mask in [u64::MAX, 0xff, 0xf000_0000_0000_0000, 0x8000_0000_0000_0001, 0x3 << 62] {
+        insert_and_remove(&mut rng, 500, 1, mask);
+    }
+    // 0 and 1 << 63 only differ in the last bit, so they are only split at the last level
+    let hashes = [0, 1 << 63, 1 << 62, 3 << 62, 1 << 60, 1 << 59, u64::MAX, u64::MAX - 1];
+    let model: StdMap<Key, usize> =
+        hashes.iter().enumerate().map(|(i, &hash)| (Key { hash, id: 0 }, i)).collect();
+    let mut map = HashMap::with_hasher(Exact);
+    for (&k, &v) in &model {
+        map = map.insert(k, v);
+    }
+    check(&map, &model);
+    for k in model.keys() {
+        assert!(!map.remove(k).contains_key(k));
+        check_node(&map.remove(k).map, 0, 0);
+    }
+}
+
+#[test]
+fn full_hash_collisions() {
+    let mut rng = Rng(5);
+    insert_and_remove(&mut rng, 1, 50, u64::MAX);
+    insert_and_remove(&mut rng, 40, 4, u64::MAX);
+    insert_and_remove(&mut rng, 200, 3, 0xf000_0000_0000_0000);
+    // a collision moves down a level when a key with another hash takes its slot, and back up
+    // when that key is removed again
+    let (a, b) = (Key { hash: 7, id: 0 }, Key { hash: 7, id: 1 });
+    let c = Key { hash: 7 | 1 << 40, id: 0 };
+    let pair = HashMap::with_hasher(Exact).insert(a, 0).insert(b, 1);
+    assert!(matches!(pair.map.nodes[..], [ref leaf] if matches!(**leaf, Buckets(_))));
+    let three = pair.insert(c, 2);
+    check(&three, &[(a, 0), (b, 1), (c, 2)].into_iter().collect());
+    assert!(same_shape(&three.remove(&c).map, &pair.map));
+    let lone = HashMap::with_hasher(Exact).insert(c, 2);
+    assert!(same_shape(&three.remove(&a).remove(&b).map, &lone.map));
+    check(&three.remove(&a), &[(b, 1), (c, 2)].into_iter().collect());
+}
+
+/// Check that `map` has the same shape as, and is `==` to, a map built from scratch with its
+/// entries and hasher. Comparing, subset checks and merges all walk two tries slot by slot, so
+/// they rely on every operation leaving its result in this one canonical shape.
+fn check_canonical<K,V,S,P>(map: &HashMap<K,V,S,P>)
+        where K: Hash + Eq + Clone, V: PartialEq + Clone, S: BuildHasher, P: PointerKind {
+    check_node(&map.map, 0, 0);
+    let fresh = map.family().map_from(map.iter().map(|(k, v)| (k.clone(), v.clone())));
+    assert!(same_shape(&map.map, &fresh.map));
+    assert!(*map == fresh);
+}
+
+/// Run every operation that builds a new map from `a` and `b`, and check that the results are
+/// canonical
+fn check_operations<K,S>(a: &HashMap<K,u32,S>, b: &HashMap<K,u32,S>)
+        where K: Hash + Eq + Clone, S: BuildHasher {
+    check_canonical(&a.union_with(b, |_, x, y| x + y));
+    check_canonical(&a.intersection_with(b, |_, x, y| x * y));
+    let left = |_: &K, x: &u32| (x % 2 == 0).then_some(*x);
+    let both = |_: &K, x: &u32, y: &u32| (x != y).then_some(x + y);
+    check_canonical(&a.merge(b, left, both, |_, y| Some(*y)));
+    let (ka, kb) = (a.key_set(), b.key_set());
+    for set in [ka.union(&kb), ka.intersection(&kb), ka.difference(&kb)] {
+        check_canonical(&set.map);
+    }
+    check_canonical(&ka.symmetric_difference(&kb).map);
+    check_canonical(&a.restrict_keys(&kb));
+    check_canonical(&a.without_keys(&kb));
+    check_canonical(&a.filter(|_, v| v % 3 == 0));
+    check_canonical(&a.filter_map(|_, v| (v % 3 != 0).then_some(v / 3)));
+    check_canonical(&a.map_values(|v| v / 2));
+    let (yes, no) = a.partition(|_, v| v % 4 == 0);
+    check_canonical(&yes);
+    check_canonical(&no);
+    let mut t = a.transient();
+    t.extend(b.iter().map(|(k, v)| (k.clone(), *v)));
+    for k in b.keys().step_by(3) {
+        t.remove(k);
+    }
+    check_canonical(&t.persistent());
+    let mut m = a.clone();
+    for (i, k) in b.keys().enumerate().take(200) {
+        m = match (i % 3, m.entry(k.clone())) {
+            (0, Entry::Occupied(e)) => e.remove(),
+            (_, Entry::Occupied(e)) => e.insert(i as u32),
+            (_, Entry::Vacant(e)) => e.insert(i as u32)
+        };
+        m = m.alter(k.clone(), |v| v.filter(|v| *v % 5 != 0).copied());
+    }
+    check_canonical(&m);
+}
+
+#[test]
+fn results_are_canonical() {
+    let family = HasherFamily::new();
+    let a = family.map_from((0..3000).map(|i| (i, i)));
+    let b = family.map_from((2000..5000).map(|i| (i, i % 7)));
+    check_operations(&a, &b);
+    check_operations(&b, &a);
+    check_operations(&a, &a.insert(10000, 1).remove(&5).insert(7, 0));
+    check_operations(&a, &family.map());
+    // with different hashers, one side is rehashed first
+    let c: HashMap<u32, u32> = (1000..4000).map(|i| (i, i)).collect();
+    check_operations(&a, &c);
+    let mut rng = Rng(6);
+    for _ in 0..20 {
+        let (a, b) = random_pair(&mut rng, 400);
+        check_operations(&a, &b);
+        check_operations(&a, &a.remove(&rng.small_key()));
+    }
+}
+
+#[test]
+fn no_ops_keep_the_map() {
+    let m: HashMap<u32, u32> = (0..3000).map(|i| (i, i)).collect();
+    let s = m.key_set();
+    assert!(m.remove(&5000).ptr_eq(&m));
+    assert!(m.transient().persistent().ptr_eq(&m));
+    assert!(m.partition(|_, _| true).0.ptr_eq(&m));
+    assert!(m.partition(|_, _| false).1.ptr_eq(&m));
+    assert!(m.entry(5).or_insert(1).ptr_eq(&m));
+    assert!(s.union(&s).map.ptr_eq(&s.map));
+    assert!(s.intersection(&s).map.ptr_eq(&s.map));
+    assert!(s.remove(&5000).map.ptr_eq(&s.map));
+    // a version shares everything but the path it changed, and set algebra keeps what is shared
+    let union = s.union(&s.insert(5000)).map;
+    let old = &s.map.map.nodes;
+    let shared = union.map.nodes.iter().filter(|x| old.iter().any(|y| Rc::ptr_eq(x, y)));
+    assert_eq!(shared.count(), old.len() - 1);
+}
+
+#[test]
+fn extend_in_place() {
+    let base: HashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+    let mut map = base.clone();
+    map.extend((500..1500).map(|i| (i, i + 1)));
+    check(&map, &(0..1500).map(|i| (i, if i < 500 { i } else { i + 1 })).collect());
+    check(&base, &(0..1000).map(|i| (i, i)).collect());
+    // a panic in the iterator leaves an empty map behind rather than one with a wrong size
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        map.extend((0..10).map(|i| if i < 5 { (i, i) } else { panic!() }));
+    }));
+    assert!(result.is_err());
+    check(&map, &StdMap::new());
+    assert_eq!(map.iter().count(), 0);
+}
+
+#[test]
+fn transients() {
+    let base: HashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+    let mut map = base.transient();
+    let mut model: StdMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+    let mut rng = Rng(11);
+    for i in 0..5000 {
+        let k = rng.below(2000) as u32;
+        match rng.below(3) {
+            0 => assert_eq!(map.insert(k, i), model.insert(k, i)),
+            1 => assert_eq!(map.remove(&k), model.remove(&k)),
+            _ => {
+                assert_eq!(map.get(&k), model.get(&k));
+                assert_eq!(map.contains_key(&k), model.contains_key(&k));
+            }
+        }
+        assert_eq!(map.len(), model.len());
+    }
+    check(&map.persistent(), &model);
+    // the map the transient came from is left unchanged
+    check(&base, &(0..1000).map(|i| (i, i)).collect());
+    let base = base.key_set();
+    let mut set = base.transient();
+    assert!(set.insert(5000) && !set.insert(5000) && set.contains(&5000));
+    assert!(set.remove(&5) && !set.remove(&5) && !set.remove(&6000) && !set.contains(&5));
+    assert!(set.contains(&6) && set.len() == 1000);
+    let expected = (0..1000).filter(|i| *i != 5).chain([5000]).map(|i| (i, ())).collect();
+    check(&set.persistent().map, &expected);
+    assert!(base.contains(&5) && !base.contains(&5000) && base.len() == 1000);
+}
+
+/// Check the set algebra of `a` and `b` against std's
+fn check_set_ops<K,S>(a: &HashSet<K,S>, b: &HashSet<K,S>)
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    use std::collections::HashSet as StdSet;
+    let (x, y): (StdSet<K>, StdSet<K>) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+    let model = |keys: Vec<&K>| keys.into_iter().map(|k| (k.clone(), ())).collect();
+    check(&a.union(b).map, &model(x.union(&y).collect()));
+    check(&a.intersection(b).map, &model(x.intersection(&y).collect()));
+    check(&a.difference(b).map, &model(x.difference(&y).collect()));
+    check(&a.symmetric_difference(b).map, &model(x.symmetric_difference(&y).collect()));
+}
+
+#[test]
+fn set_algebra() {
+    let family = HasherFamily::new();
+    let a = family.set_from(0..3000);
+    check_set_ops(&a, &family.set_from(2000..6000));
+    check_set_ops(&a, &family.set_from(5000..6000));
+    check_set_ops(&a, &family.set());
+    check_set_ops(&a, &a);
+    // versions that share most of their subtrees
+    let b = a.insert(7000).remove(&5).remove(&2999);
+    check_set_ops(&a, &b);
+    check_set_ops(&b, &a);
+    // with different hashers, one side is rehashed first
+    check_set_ops(&a, &(1000..4000).collect());
+    // full collisions and long shared prefixes
+    let mut rng = Rng(7);
+    for _ in 0..30 {
+        let (a, b) = random_pair(&mut rng, 300);
+        let (a, b) = (a.key_set(), b.key_set());
+        check_set_ops(&a, &b);
+        check_set_ops(&a, &a.insert(rng.small_key()));
+    }
+}
+
+/// Check the comparisons of `a` and `b` against std's
+fn check_predicates<K,S>(a: &HashSet<K,S>, b: &HashSet<K,S>)
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    use std::collections::HashSet as StdSet;
+    let (x, y): (StdSet<K>, StdSet<K>) = (a.iter().cloned().collect(), b.iter().cloned().collect());
+    assert_eq!(a.is_subset(b), x.is_subset(&y));
+    assert_eq!(b.is_subset(a), y.is_subset(&x));
+    assert_eq!(a.is_superset(b), x.is_superset(&y));
+    assert_eq!(a.is_disjoint(b), x.is_disjoint(&y));
+    assert_eq!(a == b, x == y);
+}
+
+#[test]
+fn comparisons() {
+    let family = HasherFamily::new();
+    let a = family.set_from(0..3000);
+    for b in [family.set_from(1000..2000), family.set_from(3000..4000), family.set_from(0..3000)] {
+        check_predicates(&a, &b);
+    }
+    check_predicates(&a, &family.set());
+    // versions of one set
+    for b in [a.insert(5000), a.remove(&7), a.insert(5000).remove(&7), a.remove(&7).insert(7)] {
+        check_predicates(&a, &b);
+    }
+    assert!(a.remove(&7).insert(7) == a);
+    // with different hashers, the keys of one side are looked up in the other
+    check_predicates(&a, &(0..3000).collect());
+    check_predicates(&a, &(0..1000).collect());
+    check_predicates(&a, &(5000..6000).collect());
+    let mut rng = Rng(8);
+    for _ in 0..30 {
+        let (a, b) = random_pair(&mut rng, 100);
+        let (a, b) = (a.key_set(), b.key_set());
+        check_predicates(&a, &b);
+        check_predicates(&a, &a.union(&b));
+        check_predicates(&a.difference(&b), &b);
+    }
+    // maps are also equal only with the same values
+    let m = family.map_from((0..500).map(|i| (i, i)));
+    assert!(m.insert(3, 3) == m && m.insert(3, 4) != m && m.remove(&3) != m);
+    let other: HashMap<u32, u32> = (0..500).rev().map(|i| (i, i)).collect();
+    assert!(other == m && other.insert(3, 4) != m);
+}
+
+/// Check `union_with`, `intersection_with` and `merge` of `a` and `b` against merges of std maps
+fn check_merges<K,S>(a: &HashMap<K,u32,S>, b: &HashMap<K,u32,S>)
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    let x: StdMap<K,u32> = a.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    let y: StdMap<K,u32> = b.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    let mut union = x.clone();
+    for (k, v) in &y {
+        union.insert(k.clone(), x.get(k).map_or(*v, |u| u * 1000 + v));
+    }
+    check(&a.union_with(b, |_, u, v| u * 1000 + v), &union);
+    let both = x.iter().filter_map(|(k, u)| Some((k.clone(), *u as u64 + *y.get(k)? as u64 * 7)));
+    check(&a.intersection_with(b, |_, u, v| *u as u64 + *v as u64 * 7), &both.collect());
+    let mut merged = StdMap::new();
+    for (k, u) in &x {
+        let value = match y.get(k) {
+            Some(v) => (u != v).then_some(u + v),
+            None => (u % 2 == 0).then_some(*u)
+        };
+        merged.extend(value.map(|value| (k.clone(), value)));
+    }
+    for (k, v) in y.iter().filter(|(k, v)| !x.contains_key(k) && *v % 3 == 0) {
+        merged.insert(k.clone(), v * 2);
+    }
+    let left = |_: &K, u: &u32| (u % 2 == 0).then_some(*u);
+    let both = |_: &K, u: &u32, v: &u32| (u != v).then_some(u + v);
+    check(&a.merge(b, left, both, |_, v| (v % 3 == 0).then_some(v * 2)), &merged);
+}
+
+#[test]
+fn merges() {
+    let family = HasherFamily::new();
+    let a = family.map_from((0..3000).map(|i| (i, i)));
+    check_merges(&a, &family.map_from((2000..5000).map(|i| (i, i % 5))));
+    check_merges(&a, &family.map());
+    check_merges(&family.map(), &a);
+    check_merges(&a, &a.insert(7, 1).insert(9000, 3).remove(&100));
+    check_merges(&a, &(1000..4000).map(|i| (i, i % 3)).collect());
+    let mut rng = Rng(9);
+    for _ in 0..30 {
+        let (a, b) = random_pair(&mut rng, 200);
+        check_merges(&a, &b);
+        check_merges(&a, &a.insert(rng.small_key(), 4));
+    }
+}
+
+/// Check that applying the changes in `a.diff(b)` to `a` gives `b`, and that each changed key is
+/// reported once. Returns the number of changes.
+fn check_diff<K,S>(a: &HashMap<K,u32,S>, b: &HashMap<K,u32,S>) -> usize
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    let mut model: StdMap<K,u32> = a.iter().map(|(k, v)| (k.clone(), *v)).collect();
+    let mut count = 0;
+    for item in a.diff(b) {
+        count += 1;
+        match item {
+            DiffItem::Added(k, v) => assert_eq!(model.insert(k.clone(), *v), None),
+            DiffItem::Removed(k, v) => assert_eq!(model.remove(k), Some(*v)),
+            DiffItem::Changed(k, old, new) => {
+                assert_ne!(old, new);
+                assert_eq!(model.insert(k.clone(), *new), Some(*old));
+            }
+        }
+    }
+    check(b, &model);
+    count
+}
+
+#[test]
+fn diffs() {
+    let family = HasherFamily::new();
+    let a = family.map_from((0..3000).map(|i| (i, i)));
+    let b = family.map_from((2000..5000).map(|i| (i, i % 5)));
+    assert_eq!(check_diff(&a, &b), 5000);
+    assert_eq!(check_diff(&b, &a), 5000);
+    assert_eq!(check_diff(&a, &family.map()), 3000);
+    assert_eq!(check_diff(&family.map(), &a), 3000);
+    assert_eq!(check_diff(&a, &a), 0);
+    assert_eq!(check_diff(&a, &a.insert(7, 1).insert(9000, 3).remove(&100).insert(8, 8)), 3);
+    // with different hashers, the entries of each side are looked up in the other
+    let c: HashMap<u32, u32> = (1000..4000).map(|i| (i, i % 2)).collect();
+    check_diff(&a, &c);
+    check_diff(&c, &a);
+    assert_eq!(check_diff(&a, &a.iter().map(|(k, v)| (*k, *v)).collect()), 0);
+    let mut rng = Rng(10);
+    for _ in 0..30 {
+        let (a, b) = random_pair(&mut rng, 150);
+        check_diff(&a, &b);
+        check_diff(&b, &a);
+        check_diff(&a, &a.insert(rng.small_key(), 1));
+    }
+}
+
+#[test]
+fn patches() {
+    let family = HasherFamily::new();
+    let a = family.map_from((0..2000).map(|i| (i, i)));
+    let b = a.insert(5, 50).insert(3000, 1).remove(&7);
+    let c = b.insert(5, 51).remove(&3000).insert(8, 0).insert(7, 7);
+    let p = Patch::diff(&a, &b);
+    assert_eq!(p.len(), 3);
+    assert!(p.apply(&a).unwrap() == b);
+    // inverting undoes the patch, and inverting twice gives it back
+    assert!(p.invert().apply(&b).unwrap() == a);
+    assert!(p.invert().invert() == p);
+    let q = Patch::diff(&b, &c);
+    let pq = p.then(&q).unwrap();
+    assert!(pq.apply(&a).unwrap() == c && pq == Patch::diff(&a, &c));
+    // 3000 is added and removed again, and 7 is removed and put back with its old value
+    assert_eq!(pq.len(), 2);
+    assert!(pq.then(&pq.invert()).unwrap().is_empty());
+    // composing patches that don't follow each other reports every conflict
+    let sorted = |mut conflicts: Vec<Conflict<u32, u32>>| {
+        conflicts.sort_by_key(|c| c.key);
+        conflicts
+    };
+    let conflict = |key, expected, found| Conflict { key, expected, found };
+    assert_eq!(q.then(&p).err(), Some(vec![conflict(5, Some(5), Some(51))]));
+    let expected = vec![conflict(5, Some(50), Some(5)), conflict(7, None, Some(7)),
+                        conflict(3000, Some(1), None)];
+    assert_eq!(p.invert().then(&q).err().map(sorted), Some(expected));
+    // applying to a map that has diverged
+    assert_eq!(p.apply(&c).err(), Some(vec![conflict(5, Some(5), Some(51))]));
+    let expected = vec![conflict(5, Some(5), Some(50)), conflict(7, Some(7), None),
+                        conflict(3000, None, Some(1))];
+    assert_eq!(p.apply(&b).err().map(sorted), Some(expected.clone()));
+    // the conflicts are the same whatever order the trie puts the keys in
+    for _ in 0..4 {
+        let family = HasherFamily::new();
+        let rebuild = |m: &HashMap<u32, u32>| family.map_from(m.iter().map(|(k, v)| (*k, *v)));
+        let (a, b) = (rebuild(&a), rebuild(&b));
+        assert_eq!(Patch::diff(&a, &b).apply(&b).err().map(sorted), Some(expected.clone()));
+    }
+    let err = p.apply(&a.insert(3000, 0)).err().unwrap();
+    assert_eq!(err[0].to_string(), "conflict at key 3000: expected None, found Some(0)");
+    assert!(p.force_apply(&c) == c.insert(5, 50).insert(3000, 1).remove(&7));
+    // the edits round-trip in any order, and conflicting edits are rejected
+    let mut edits = pq.to_edits();
+    edits.reverse();
+    assert!(Patch::from_edits(edits).unwrap() == pq);
+    let edits = [Edit::Insert { key: 1, value: 2 }, Edit::Replace { key: 1, old: 3, new: 4 }];
+    let err = Patch::<u32, u32>::from_edits(edits).err().unwrap();
+    assert_eq!(err, Conflict { key: 1, expected: Some(3), found: Some(2) });
+    let edits = [Edit::Insert { key: 1, value: 2 }, Edit::Remove { key: 1, old: 2 }];
+    assert!(Patch::<u32, u32>::from_edits(edits).unwrap().is_empty());
+    let single = Patch::diff(&a, &a.insert(3, 4));
+    assert_eq!(format!("{:?}", single), "[Replace { key: 3, old: 3, new: 4 }]");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn patch_encoding() {
+    let a: HashMap<u32, u32, FixedState> = (0..100).map(|i| (i, i)).collect();
+    let b = a.insert(5, 50).insert(300, 1).remove(&7);
+    let p = Patch::diff(&a, &b);
+    let json = serde_json::to_string(&p).unwrap();
+    // `FixedState` lists the edits in the same order every time
+    let golden = r#"[{"Remove":{"key":7,"old":7}},{"Replace":{"key":5,"old":5,"new":50}},"#;
+    assert_eq!(json, golden.to_string() + r#"{"Insert":{"key":300,"value":1}}]"#);
+    let q: Patch<u32, u32, FixedState> = serde_json::from_str(&json).unwrap();
+    assert!(q == p && q.apply(&a).unwrap() == b);
+    // a patch with another hasher reads the edits in its own order
+    let r: Patch<u32, u32> = serde_json::from_str(&json).unwrap();
+    let mut edits = r.to_edits();
+    edits.sort_by_key(|e| match e { Edit::Insert { key, .. } | Edit::Remove { key, .. }
+                                    | Edit::Replace { key, .. } => *key });
+    assert_eq!(edits, [Edit::Replace { key: 5, old: 5, new: 50 }, Edit::Remove { key: 7, old: 7 },
+                       Edit::Insert { key: 300, value: 1 }]);
+    let conflicting = r#"[{"Insert":{"key":1,"value":2}},{"Remove":{"key":1,"old":3}}]"#;
+    assert!(serde_json::from_str::<Patch<u32, u32>>(conflicting).is_err());
+    let conflict = Conflict { key: 1, expected: Some(2), found: None };
+    let json = serde_json::to_string(&conflict).unwrap();
+    assert_eq!(json, r#"{"key":1,"expected":2,"found":null}"#);
+    assert_eq!(serde_json::from_str::<Conflict<u32, u32>>(&json).unwrap(), conflict);
+}
+
+#[test]
+fn entries() {
+    let (a, b) = (Key { hash: 1, id: 0 }, Key { hash: 1, id: 1 });
+    let (c, d) = (Key { hash: 1 | 1 << 20, id: 0 }, Key { hash: 2, id: 0 });
+    let base = HashMap::with_hasher(Exact).insert(a, 0).insert(d, 3);
+    // the result of each entry operation has the same shape as with `insert` or `remove`
+    let same = |m: HashMap<Key, u32, Exact>, expected: HashMap<Key, u32, Exact>| {
+        check(&m, &expected.iter().map(|(k, v)| (*k, *v)).collect());
+        assert!(same_shape(&m.map, &expected.map));
+    };
+    fn vacant(m: &HashMap<Key, u32, Exact>, k: Key) -> VacantEntry<'_, Key, u32, Exact> {
+        match m.entry(k) {
+            Entry::Vacant(e) => {
+                assert_eq!(*e.key(), k);
+                e
+            }
+            Entry::Occupied(_) => panic!("{:?} is in the map", k)
+        }
+    }
+    fn occupied(m: &HashMap<Key, u32, Exact>, k: Key) -> OccupiedEntry<'_, Key, u32, Exact> {
+        match m.entry(k) {
+            Entry::Occupied(e) => e,
+            Entry::Vacant(_) => panic!("{:?} is not in the map", k)
+        }
+    }
+    // into an empty slot, into a slot with a key of another hash, and into a full collision
+    let empty = HashMap::with_hasher(Exact);
+    same(vacant(&empty, a).insert(0), empty.insert(a, 0));
+    same(vacant(&base, c).insert(2), base.insert(c, 2));
+    same(vacant(&base, b).insert(1), base.insert(b, 1));
+    let full = base.insert(b, 1).insert(c, 2);
+    same(vacant(&full, Key { hash: 1, id: 2 }).insert(5), full.insert(Key { hash: 1, id: 2 }, 5));
+    assert_eq!(vacant(&full, Key { hash: 9, id: 0 }).into_key(), Key { hash: 9, id: 0 });
+    // replacing and removing keys that are inline, in a collision, and a level down
+    for k in [a, b, c, d] {
+        let e = occupied(&full, k);
+        assert_eq!((e.key(), e.get()), (&k, full.get(&k).unwrap()));
+        same(e.insert(9), full.insert(k, 9));
+        same(occupied(&full, k).remove(), full.remove(&k));
+        let without_d = full.remove(&d);
+        if k != d {
+            same(occupied(&without_d, k).remove(), without_d.remove(&k));
+        }
+    }
+    same(occupied(&base, a).remove(), base.remove(&a));
+    same(occupied(&base, a).remove().entry(d).and_modify(|v| *v += 1), empty.insert(d, 4));
+    same(full.entry(Key { hash: 3, id: 0 }).or_insert(7), full.insert(Key { hash: 3, id: 0 }, 7));
+    assert!(full.entry(b).or_insert_with(|| panic!()).ptr_eq(&full));
+    assert!(full.entry(Key { hash: 3, id: 0 }).and_modify(|_| panic!()).ptr_eq(&full));
+    // against std, with random keys
+    let mut rng = Rng(11);
+    let mut map = HashMap::with_hasher(Exact);
+    let mut model = StdMap::new();
+    for i in 0..3000 {
+        let k = rng.key(300, 3, 0xf000_0000_0000_00ff);
+        map = match map.entry(k) {
+            Entry::Occupied(e) if i % 2 == 0 => e.remove(),
+            Entry::Occupied(e) => e.insert(i),
+            Entry::Vacant(e) => e.insert(i)
+        };
+        match model.entry(k) {
+            std::collections::hash_map::Entry::Occupied(e) if i % 2 == 0 => drop(e.remove()),
+            std::collections::hash_map::Entry::Occupied(mut e) => drop(e.insert(i)),
+            std::collections::hash_map::Entry::Vacant(e) => drop(e.insert(i))
+        }
+        if i % 300 == 0 {
+            check(&map, &model);
+        }
+    }
+    check(&map, &model);
+}
+
+#[test]
+fn alter_no_ops() {
+    let m: HashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+    assert!(m.alter(5, |v| v.copied()).ptr_eq(&m));
+    assert!(m.alter(5000, |_| None).ptr_eq(&m));
+    assert!(m.adjust(&5, |v| *v).ptr_eq(&m));
+    assert!(m.adjust(&5000, |_| panic!()).ptr_eq(&m));
+    assert!(m.update(&5, |v| Some(*v)).ptr_eq(&m));
+    assert!(m.update(&5000, |_| panic!()).ptr_eq(&m));
+    assert!(m.insert_with(5, 9, |_, old| *old).ptr_eq(&m));
+    assert!(!m.insert(5, 5).ptr_eq(&m));
+    // and the changes they do make
+    let model = |changes: &[(u32, Option<u32>)]| {
+        let mut model: StdMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        for &(k, v) in changes {
+            match v {
+                Some(v) => model.insert(k, v),
+                None => model.remove(&k)
+            };
+        }
+        model
+    };
+    check(&m.alter(5, |v| v.map(|v| v + 1)), &model(&[(5, Some(6))]));
+    check(&m.alter(5000, |_| Some(1)), &model(&[(5000, Some(1))]));
+    check(&m.alter(5, |_| None), &model(&[(5, None)]));
+    check(&m.adjust(&3, |v| v * 10), &model(&[(3, Some(30))]));
+    check(&m.update(&3, |_| None), &model(&[(3, None)]));
+    check(&m.insert_with(3, 4, |new, old| new + old), &model(&[(3, Some(7))]));
+    check(&m.insert_with(3000, 4, |_, _| panic!()), &model(&[(3000, Some(4))]));
+    let collisions = HashMap::with_hasher(Exact).insert(Key { hash: 1, id: 0 }, 0);
+    let collisions = collisions.insert(Key { hash: 1, id: 1 }, 1);
+    assert!(collisions.adjust(&Key { hash: 1, id: 1 }, |v| *v).ptr_eq(&collisions));
+    assert!(collisions.alter(Key { hash: 1, id: 2 }, |_| None).ptr_eq(&collisions));
+}
+
+/// Check that `try_map` gives the same map as `map_values_with_key` when `f` succeeds, and
+/// otherwise returns the first error without calling `f` again
+fn check_try_map<K,S>(map: &HashMap<K,u32,S>, fails: impl Fn(&K) -> bool)
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    let expected = map.map_values_with_key(|k, v| (k.clone(), v + 1));
+    assert!(same_shape(&map.map, &expected.map));
+    check(&expected, &map.iter().map(|(k, v)| (k.clone(), (k.clone(), v + 1))).collect());
+    let (mut calls, mut failed) = (0, None);
+    let result = map.try_map(|k, v| {
+        assert!(failed.is_none(), "f was called again after {:?}", failed);
+        calls += 1;
+        if fails(k) {
+            failed = Some(k.clone());
+            return Err(k.clone());
+        }
+        Ok((k.clone(), v + 1))
+    });
+    match map.keys().position(&fails) {
+        Some(i) => assert!(result.err() == failed && failed.is_some() && calls == i + 1),
+        None => assert!(result.unwrap() == expected && calls == map.len())
+    }
+}
+
+#[test]
+fn value_maps() {
+    let map: HashMap<u32, u32> = (0..3000).map(|i| (i, i)).collect();
+    check_try_map(&map, |_| false);
+    check_try_map(&map, |k| k % 1000 == 7);
+    check_try_map(&map, |_| true);
+    check_try_map(&HashMap::<u32, u32>::new(), |_| true);
+    let mut rng = Rng(13);
+    for _ in 0..20 {
+        let (a, _) = random_pair(&mut rng, 150);
+        let id = rng.below(4) as u32;
+        check_try_map(&a, |k| k.id == id && k.hash & 1 == 1);
+    }
+}
+
+/// Check `partition`, `split_off_by` and `retain` of `map` and of its key set against std's
+/// `partition`, and that a child of the root whose keys all go to one side is shared with it
+fn check_partition<K,S>(map: &HashMap<K,u32,S>, f: impl Fn(&K, &u32) -> bool)
+        where K: Hash + Eq + Clone + Debug, S: BuildHasher {
+    let (yes, no): (StdMap<K,u32>, StdMap<K,u32>) =
+        map.iter().map(|(k, v)| (k.clone(), *v)).partition(|(k, v)| f(k, v));
+    let (a, b) = map.partition(&f);
+    check(&a, &yes);
+    check(&b, &no);
+    let mut rest = map.clone();
+    check(&rest.split_off_by(&f), &yes);
+    check(&rest, &no);
+    // the keys in a child of the root are the ones with its slot in their lowest hash bits
+    let slots = (0..BRANCH_FACTOR).filter(|s| map.map.nodemap & 1 << s != 0);
+    for (slot, child) in slots.zip(&map.map.nodes) {
+        let below = |(k, _): &(&K, &u32)| split_hash(map.hasher().hash_one(k), 0) == slot;
+        let side = match map.iter().filter(below).map(|(k, v)| f(k, v)).collect::<Vec<_>>() {
+            sides if sides.iter().all(|x| *x) => &a,
+            sides if sides.iter().all(|x| !*x) => &b,
+            _ => continue
+        };
+        assert!(side.map.nodes.iter().any(|n| Rc::ptr_eq(n, child)));
+    }
+    // the same with the set of keys, where `f` gets each key's value from `map`
+    let g = |k: &K| f(k, map.get(k).unwrap());
+    let set = map.key_set();
+    let unit = |m: &StdMap<K,u32>| m.keys().map(|k| (k.clone(), ())).collect();
+    let (c, d) = set.partition(g);
+    check(&c.map, &unit(&yes));
+    check(&d.map, &unit(&no));
+    let mut rest = set.clone();
+    check(&rest.split_off_by(g).map, &unit(&yes));
+    check(&rest.map, &unit(&no));
+    let mut kept = set.clone();
+    kept.retain(g);
+    check(&kept.map, &unit(&yes));
+    // when everything goes to one side, that side is the map itself
+    if no.is_empty() {
+        assert!(a.ptr_eq(map) && c.map.ptr_eq(&set.map));
+    }
+    if yes.is_empty() && !no.is_empty() {
+        assert!(b.ptr_eq(map) && d.map.ptr_eq(&set.map) && rest.map.ptr_eq(&set.map));
+    }
+}
+
+#[test]
+fn partitions() {
+    let map: HashMap<u32, u32> = (0..3000).map(|i| (i, i)).collect();
+    check_partition(&map, |_, v| v % 3 == 0);
+    check_partition(&map, |k, _| *k < 10);
+    check_partition(&map, |_, _| true);
+    check_partition(&map, |_, _| false);
+    check_partition(&HashMap::<u32, u32>::new(), |_, _| true);
+    // split by the slot each key has at the root, so that whole subtrees go to one side
+    let spread = |i: u64| Key { hash: i.wrapping_mul(0x9e37_79b9_7f4a_7c15), id: 0 };
+    let map: HashMap<Key, u32, Exact> = (0..3000).map(|i| (spread(i), i as u32)).collect();
+    check_partition(&map, |k, _| k.hash & 0x1f < 10);
+    check_partition(&map, |k, v| k.hash & 0x1f < 10 && v % 2 == 0);
+    let mut rng = Rng(14);
+    for _ in 0..20 {
+        let (a, _) = random_pair(&mut rng, 200);
+        check_partition(&a, |k, _| k.hash & 1 == 0);
+        check_partition(&a, |k, v| k.id == 1 || *v > 6);
+    }
+}
+
+#[test]
+fn set_to_map() {
+    let set: HashSet<u32> = (0..3000).collect();
+    let map = set.to_map(|v| v * 2);
+    assert!(same_shape(&set.map.map, &map.map) && map.same_hasher(&set.map));
+    check(&map, &(0..3000).map(|i| (i, i * 2)).collect());
+    assert!(map.key_set() == set && same_shape(&map.key_set().map.map, &set.map.map));
+    assert!(HashSet::<u32>::new().to_map(|_| 0u8).is_empty());
+    let mut rng = Rng(15);
+    for _ in 0..10 {
+        let set = random_pair(&mut rng, 300).0.key_set();
+        let map = set.to_map(|k| k.id * 10);
+        assert!(same_shape(&set.map.map, &map.map));
+        check(&map, &set.iter().map(|k| (*k, k.id * 10)).collect());
+    }
+}
+
+#[test]
+fn hasher_families() {
+    let family = HasherFamily::with_hasher(FixedState::new());
+    let a = family.map_from((0..3000).map(|i| (i, i)));
+    let b = family.set_from(1000..2000);
+    assert!(a.family().ptr_eq(&family) && b.family().ptr_eq(&family));
+    assert!(a.same_hasher(&b.map) && a.filter(|_, v| v % 2 == 0).same_hasher(&a));
+    // equal hashers that aren't shared still give the right results, by rehashing
+    let c: HashMap<u32, u32, FixedState> = (0..3000).map(|i| (i, i)).collect();
+    let d: HashSet<u32, FixedState> = (1000..2000).collect();
+    assert!(!c.same_hasher(&a) && c == a);
+    let expected = (0..3000).filter(|i| !(1000..2000).contains(i)).map(|i| (i, i)).collect();
+    check(&a.without_keys(&b), &expected);
+    check(&a.without_keys(&d), &expected);
+    check(&c.without_keys(&b), &expected);
+    // the subtrees of a map with a shared hasher go into a union whole, while a map with its own
+    // hasher has to be rebuilt first
+    let shared = |x: &HashMap<u32, u32, FixedState>| {
+        let union = family.map().union_with(x, |_, v, _| *v);
+        union.map.nodes.iter().filter(|n| x.map.nodes.iter().any(|m| Rc::ptr_eq(n, m))).count()
+    };
+    assert_eq!(shared(&a), a.map.nodes.len());
+    assert_eq!(shared(&c), 0);
+}
+
+#[test]
+fn sync_snapshots() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SyncHashMap<String, u32>>();
+    assert_send_sync::<SyncHashSet<String>>();
+    assert_send_sync::<SyncHasherFamily>();
+    assert_send_sync::<TransientHashMap<String, u32, RandomState, ArcPointer>>();
+    let family = SyncHasherFamily::new_sync();
+    let map = family.map_from((0..3000u64).map(|i| (i, i)));
+    let set = family.set_from(0..3000);
+    // taking a snapshot copies nothing, and another thread can read it while this one goes on
+    let snapshot = map.clone();
+    assert!(Arc::ptr_eq(&snapshot.map, &map.map));
+    let reader = {
+        let (snapshot, set) = (snapshot.clone(), set.clone());
+        std::thread::spawn(move || {
+            snapshot.len() == 3000 && (0..3000).all(|i| snapshot.get(&i) == Some(&i))
+                && snapshot.key_set() == set
+        })
+    };
+    let map = map.insert(5, 50).remove(&6);
+    assert!(reader.join().unwrap());
+    // several threads can also borrow one snapshot at once
+    let total: u64 = std::thread::scope(|scope| {
+        let readers: Vec<_> = (0..4).map(|t| {
+            let snapshot = &snapshot;
+            scope.spawn(move || {
+                snapshot.iter().filter(|(k, _)| *k % 4 == t).map(|(_, v)| *v).sum::<u64>()
+            })
+        }).collect();
+        readers.into_iter().map(|r| r.join().unwrap()).sum()
+    });
+    assert_eq!(total, (0..3000).sum::<u64>());
+    assert!(snapshot.get(&6) == Some(&6) && map.get(&6).is_none());
+}
+
+#[test]
+fn fixed_order() {
+    // these values are what `FixedState` promises to give on every target and in every run, so
+    // they must never change
+    let hash = |seed: u64, write: &dyn Fn(&mut FixedHasher)| {
+        let mut hasher = FixedState::with_seed(seed).build_hasher();
+        write(&mut hasher);
+        hasher.finish()
+    };
+    assert_eq!(hash(0, &|h| h.write_u64(1)), 0x37e8_d294_6949_7cd2);
+    assert_eq!(hash(0, &|h| h.write_u32(7)), 0x1e41_f903_378a_420a);
+    assert_eq!(hash(1, &|h| h.write_u64(0)), 0xed78_f21c_5df1_aaf5);
+    assert_eq!(hash(0, &|h| h.write(b"hello, world")), 0xd3e7_46c7_c051_69d5);
+    assert_eq!(hash(0, &|h| h.write_u128(u128::MAX / 3)), 0x32b1_47e6_7fb8_c5f4);
+    assert_eq!(hash(0, &|h| h.write_usize(5)), hash(0, &|h| h.write_u64(5)));
+    assert_eq!(hash(0, &|h| h.write_isize(-5)), hash(0, &|h| h.write_i64(-5)));
+    assert_ne!(hash(0, &|h| h.write(b"a\0")), hash(0, &|h| h.write(b"a")));
+    let map: HashMap<u32, char, FixedState> =
+        (0..10).map(|i| (i, (b'a' + i as u8) as char)).collect();
+    let golden = "{0: 'a', 8: 'i', 2: 'c', 3: 'd', 7: 'h', 4: 'e', 1: 'b', 6: 'g', 5: 'f', 9: 'j'}";
+    assert_eq!(format!("{:?}", map), golden);
+    let set: HashSet<u32, FixedState> = (0..10).rev().collect();
+    assert_eq!(format!("{:?}", set), "{0, 8, 2, 3, 7, 4, 1, 6, 5, 9}");
+    // the order doesn't depend on how the map was built
+    let mut rng = Rng(12);
+    let keys: Vec<u64> = (0..5000).map(|_| rng.below(1 << 40)).collect();
+    let forward: HashSet<u64, FixedState> = keys.iter().copied().collect();
+    let backward =
+        keys.iter().rev().fold(HashSet::with_hasher(FixedState::new()), |s, k| s.insert(*k));
+    let extra = forward.insert(1 << 50).remove(&(1 << 50));
+    assert!(forward.iter().eq(backward.iter()) && forward.iter().eq(extra.iter()));
+}